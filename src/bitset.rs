@@ -0,0 +1,131 @@
+use crate::IpReprError;
+
+/// Fixed size set of row ids, as returned by filters over an encoded column.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BitSet {
+    words: Vec<u64>,
+    max_value: u32,
+}
+
+impl BitSet {
+    /// Creates an empty `BitSet` that can hold the values in `0..max_value`.
+    pub fn with_max_value(max_value: u32) -> BitSet {
+        BitSet {
+            words: vec![0u64; (max_value as usize).div_ceil(64)],
+            max_value,
+        }
+    }
+
+    /// Panics if `val >= self.max_value()`.
+    pub fn insert(&mut self, val: u32) {
+        assert!(val < self.max_value, "{val} out of bounds");
+        self.words[val as usize / 64] |= 1u64 << (val % 64);
+    }
+
+    pub fn contains(&self, val: u32) -> bool {
+        val < self.max_value && self.words[val as usize / 64] & (1u64 << (val % 64)) != 0
+    }
+
+    pub fn max_value(&self) -> u32 {
+        self.max_value
+    }
+
+    /// Returns the number of values in the set.
+    pub fn len(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|&word| word == 0)
+    }
+
+    pub(crate) fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    /// Writes the words of the set, in little endian.
+    pub(crate) fn serialize(&self, output: &mut Vec<u8>) {
+        for word in &self.words {
+            output.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    /// Reads a set written by `serialize`, and returns it with the remaining data.
+    pub(crate) fn deserialize(max_value: u32, data: &[u8]) -> Result<(BitSet, &[u8]), IpReprError> {
+        let num_words = (max_value as usize).div_ceil(64);
+        if data.len() < num_words * 8 {
+            return Err(IpReprError::TruncatedData);
+        }
+        let (words_data, data) = data.split_at(num_words * 8);
+        let words: Vec<u64> = words_data
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        if let Some(&last_word) = words.last() {
+            if !max_value.is_multiple_of(64) && last_word >> (max_value % 64) != 0 {
+                return Err(IpReprError::CorruptedData);
+            }
+        }
+        Ok((BitSet { words, max_value }, data))
+    }
+
+    /// Iterates over the values of the set, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.words.iter().enumerate().flat_map(|(word_idx, &word)| {
+            let mut word = word;
+            std::iter::from_fn(move || {
+                if word == 0 {
+                    return None;
+                }
+                let bit = word.trailing_zeros();
+                word &= word - 1;
+                Some(word_idx as u32 * 64 + bit)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitset() {
+        let mut bitset = BitSet::with_max_value(130);
+        assert!(bitset.is_empty());
+        bitset.insert(0);
+        bitset.insert(64);
+        bitset.insert(129);
+        bitset.insert(64);
+        assert_eq!(bitset.len(), 3);
+        assert!(bitset.contains(129));
+        assert!(!bitset.contains(128));
+        assert!(!bitset.contains(1000));
+        assert_eq!(bitset.iter().collect::<Vec<_>>(), vec![0, 64, 129]);
+    }
+
+    #[test]
+    fn test_bitset_serialize() {
+        let mut bitset = BitSet::with_max_value(130);
+        bitset.insert(3);
+        bitset.insert(129);
+        let mut output = Vec::new();
+        bitset.serialize(&mut output);
+        assert_eq!(output.len(), 24);
+        output.push(42);
+        let (deserialized, data) = BitSet::deserialize(130, &output).unwrap();
+        assert_eq!(deserialized, bitset);
+        assert_eq!(data, &[42]);
+        assert_eq!(
+            BitSet::deserialize(130, &output[..23]),
+            Err(IpReprError::TruncatedData)
+        );
+        assert_eq!(
+            BitSet::deserialize(129, &output),
+            Err(IpReprError::CorruptedData)
+        );
+    }
+}
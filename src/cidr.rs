@@ -0,0 +1,100 @@
+use std::{net::IpAddr, ops::RangeInclusive, str::FromStr};
+
+use crate::{ip_addr_to_u128, IpReprError};
+
+/// An ip prefix such as `10.0.0.0/8` or `2001:db8::/32`.
+///
+/// Like the values of a column, IPv4 prefixes live in the IPv4-mapped IPv6
+/// space, so `10.0.0.0/8` covers `::ffff:10.0.0.0/104`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: u128,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Creates a `Cidr` from an address and a prefix length relative to that
+    /// address family. Host bits are cleared.
+    pub fn new(ip_addr: IpAddr, prefix_len: u8) -> Result<Cidr, IpReprError> {
+        let (max_prefix_len, offset) = match ip_addr {
+            IpAddr::V4(_) => (32, 96),
+            IpAddr::V6(_) => (128, 0),
+        };
+        if prefix_len > max_prefix_len {
+            return Err(IpReprError::InvalidCidr(format!("{ip_addr}/{prefix_len}")));
+        }
+        let prefix_len = prefix_len + offset;
+        let network = ip_addr_to_u128(ip_addr) & Cidr::mask(prefix_len);
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+
+    fn mask(prefix_len: u8) -> u128 {
+        u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0)
+    }
+
+    /// Returns the range of ip addresses covered by the prefix.
+    pub fn range(&self) -> RangeInclusive<u128> {
+        self.network..=self.network | !Cidr::mask(self.prefix_len)
+    }
+
+    pub fn contains(&self, ip_addr: u128) -> bool {
+        ip_addr & Cidr::mask(self.prefix_len) == self.network
+    }
+}
+
+/// Parses `addr/prefix_len`, or a single address.
+impl FromStr for Cidr {
+    type Err = IpReprError;
+
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+        let invalid_cidr = || IpReprError::InvalidCidr(cidr.to_string());
+        let (ip_addr, prefix_len) = match cidr.trim().split_once('/') {
+            Some((ip_addr, prefix_len)) => {
+                let ip_addr = IpAddr::from_str(ip_addr).map_err(|_| invalid_cidr())?;
+                let prefix_len = u8::from_str(prefix_len).map_err(|_| invalid_cidr())?;
+                (ip_addr, prefix_len)
+            }
+            None => {
+                let ip_addr = IpAddr::from_str(cidr.trim()).map_err(|_| invalid_cidr())?;
+                let prefix_len = if ip_addr.is_ipv4() { 32 } else { 128 };
+                (ip_addr, prefix_len)
+            }
+        };
+        Cidr::new(ip_addr, prefix_len).map_err(|_| invalid_cidr())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cidr() {
+        let cidr = Cidr::from_str("10.0.0.0/8").unwrap();
+        assert_eq!(cidr.range(), 0xffff_0a00_0000..=0xffff_0aff_ffff);
+        assert!(cidr.contains(0xffff_0a01_0203));
+        assert!(!cidr.contains(0x0a01_0203));
+
+        let cidr = Cidr::from_str("2001:db8::/32").unwrap();
+        assert_eq!(cidr.range().start() >> 96, 0x2001_0db8);
+        assert_eq!(cidr.range().end() >> 96, 0x2001_0db8);
+        assert_eq!(*cidr.range().end() & 0xffff_ffff, 0xffff_ffff);
+
+        let cidr = Cidr::from_str("10.1.2.3/16").unwrap();
+        assert_eq!(cidr, Cidr::from_str("10.1.0.0/16").unwrap());
+
+        let cidr = Cidr::from_str("::/0").unwrap();
+        assert_eq!(cidr.range(), 0..=u128::MAX);
+
+        let cidr = Cidr::from_str("192.168.1.1").unwrap();
+        assert_eq!(cidr.range(), 0xffff_c0a8_0101..=0xffff_c0a8_0101);
+
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Cidr::from_str("::/129").is_err());
+        assert!(Cidr::from_str("10.0.0/8").is_err());
+        assert!(Cidr::from_str("10.0.0.0/").is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use std::borrow::Cow;
+
+use crate::{
+    encode_container, is_ipv4_only, to_ipv4_domain, DictionaryEncoding, EstimatedSize, HalfDict,
+    HalfDictQ, IntervalEncoding, IpRepr, IpReprError, RunLengthEncoding, ZstdEncoding,
+};
+
+/// Size of the contiguous blocks making up a sample. Blocks preserve the locality
+/// of the column, which codecs working per block depend on.
+const SAMPLE_BLOCK_LEN: usize = 1024;
+const MAX_SAMPLE_LEN: usize = 64 * SAMPLE_BLOCK_LEN;
+
+/// Returns a deterministic sample of at most `MAX_SAMPLE_LEN` values, made of
+/// evenly spaced blocks of `SAMPLE_BLOCK_LEN` contiguous values.
+pub(crate) fn sample(ip_addrs: &[u128]) -> Cow<'_, [u128]> {
+    if ip_addrs.len() <= MAX_SAMPLE_LEN {
+        return Cow::Borrowed(ip_addrs);
+    }
+    let num_blocks = MAX_SAMPLE_LEN / SAMPLE_BLOCK_LEN;
+    let stride = (ip_addrs.len() - SAMPLE_BLOCK_LEN) / (num_blocks - 1);
+    let sample = (0..num_blocks)
+        .flat_map(|block| {
+            let start = block * stride;
+            ip_addrs[start..start + SAMPLE_BLOCK_LEN].iter().cloned()
+        })
+        .collect();
+    Cow::Owned(sample)
+}
+
+fn candidate_codecs() -> Vec<Box<dyn IpRepr>> {
+    vec![
+        Box::new(IntervalEncoding::default()),
+        Box::new(HalfDict::new(1024, 16).with_adaptive_dict_size(true)),
+        Box::new(HalfDictQ::new(1 << 16).with_adaptive_dict_size(true)),
+        Box::new(ZstdEncoding::default()),
+        Box::new(RunLengthEncoding::default()),
+        Box::new(DictionaryEncoding::default()),
+    ]
+}
+
+/// Returns the codec with the smallest estimated size for `ip_addrs`.
+///
+/// The sizes are estimated on the values encoded by `encode_container`, which
+/// maps IPv4-only columns to the 32-bit domain.
+pub fn best_codec(ip_addrs: &[u128]) -> Box<dyn IpRepr> {
+    let sample = sample(ip_addrs);
+    let sample = if is_ipv4_only(ip_addrs) {
+        Cow::Owned(to_ipv4_domain(&sample))
+    } else {
+        sample
+    };
+    candidate_codecs()
+        .into_iter()
+        // A codec that cannot encode the sample is not a candidate.
+        .filter_map(|codec| {
+            let (_data, stats) = codec.encode_with_stats(&sample).ok()?;
+            let num_bytes = EstimatedSize::extrapolate(&stats, ip_addrs.len()).num_bytes;
+            Some((num_bytes, codec))
+        })
+        .min_by_key(|(num_bytes, _)| *num_bytes)
+        .map(|(_, codec)| codec)
+        .unwrap_or_else(|| Box::new(IntervalEncoding::default()))
+}
+
+/// Encodes `ip_addrs` with the codec returned by `best_codec`, in a container
+/// that can be read back with `decode_any`.
+pub fn encode_best(ip_addrs: &[u128]) -> Result<Vec<u8>, IpReprError> {
+    let codec = best_codec(ip_addrs);
+    encode_container(&*codec, ip_addrs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_any, tests::random_vals, ContainerHeader};
+
+    #[test]
+    fn test_sample() {
+        let ip_addrs: Vec<u128> = (0..1000u128).collect();
+        assert_eq!(&*sample(&ip_addrs), &ip_addrs[..]);
+        let ip_addrs: Vec<u128> = (0..1_000_000u128).collect();
+        let sample = sample(&ip_addrs);
+        assert_eq!(sample.len(), MAX_SAMPLE_LEN);
+        assert_eq!(sample[0], 0);
+        assert!(*sample.last().unwrap() > 999_999 - SAMPLE_BLOCK_LEN as u128);
+        assert!(sample.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(&sample[..SAMPLE_BLOCK_LEN], &ip_addrs[..SAMPLE_BLOCK_LEN]);
+    }
+
+    #[test]
+    fn test_best_codec_is_smallest() {
+        let datasets: Vec<Vec<u128>> = vec![
+            (0..5000u128).map(|i| 0xffff_0a00_0000 + i).collect(),
+            (0..5000u128)
+                .map(|i| 0xffff_0a00_0000 + (i * i * 7919) % 100_000_000)
+                .collect(),
+            (0..5000u128).map(|i| (i % 3) << 100).collect(),
+        ];
+        for ip_addrs in datasets {
+            let codec = best_codec(&ip_addrs);
+            // The values encoded by `encode_best`.
+            let encoded_vals = if is_ipv4_only(&ip_addrs) {
+                to_ipv4_domain(&ip_addrs)
+            } else {
+                ip_addrs.clone()
+            };
+            let num_bytes = codec.encode(&encoded_vals).unwrap().len();
+            for candidate in candidate_codecs() {
+                assert!(num_bytes <= candidate.encode(&encoded_vals).unwrap().len());
+            }
+            let data = encode_best(&ip_addrs).unwrap();
+            let (header, _) = ContainerHeader::open(&data).unwrap();
+            assert_eq!(header.codec_id, codec.codec_id());
+            assert_eq!(decode_any(&data).unwrap(), ip_addrs);
+        }
+    }
+
+    #[test]
+    fn test_estimate_is_exact_on_small_columns() {
+        let ip_addrs: Vec<u128> = (0..5000u128)
+            .map(|i| 0xffff_0a00_0000 + (i * i * 7919) % 100_000)
+            .collect();
+        for codec in candidate_codecs() {
+            let estimate = codec.estimate(&ip_addrs).unwrap();
+            assert!(estimate.is_exact());
+            assert_eq!(estimate.num_bytes, codec.encode(&ip_addrs).unwrap().len());
+        }
+    }
+
+    #[test]
+    fn test_estimate_error_bound() {
+        let num_vals = 300_000;
+        let datasets: Vec<Vec<u128>> = vec![
+            // uniform over a few /16 subnets
+            random_vals(num_vals, 1)
+                .map(|x| 0xffff_0000_0000 + (((x % 4) << 24) | (x >> 48)) as u128)
+                .collect(),
+            // skewed, 70% of the rows on a few hot ips
+            random_vals(num_vals, 2)
+                .map(|x| {
+                    let ip = if x % 10 < 7 { x % 50 } else { x >> 44 };
+                    0xffff_0a00_0000 + ip as u128
+                })
+                .collect(),
+        ];
+        for ip_addrs in datasets {
+            for codec in candidate_codecs() {
+                let estimate = codec.estimate(&ip_addrs).unwrap();
+                assert_eq!(estimate.sample_len, MAX_SAMPLE_LEN);
+                assert_eq!(estimate, codec.estimate(&ip_addrs).unwrap());
+                let num_bytes = codec.encode(&ip_addrs).unwrap().len();
+                let relative_error =
+                    (estimate.num_bytes as f64 - num_bytes as f64).abs() / num_bytes as f64;
+                assert!(
+                    relative_error < 0.25,
+                    "{codec:?}: estimated {} actual {num_bytes}",
+                    estimate.num_bytes
+                );
+            }
+        }
+    }
+}
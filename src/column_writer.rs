@@ -0,0 +1,273 @@
+use std::{io::Write, net::IpAddr};
+
+use fnv::FnvHashMap;
+
+use crate::{
+    container::write_container, get_most_common, ip_addr_to_u128, is_ipv4_only, to_ipv4_domain,
+    CompressionStats, IPWithCount, IpRepr, IpReprError,
+};
+
+/// Number of occurrences of each distinct value of a column.
+///
+/// This is what the codecs are trained on: the sorted distinct values for the
+/// interval table, and the most common values for the dictionaries.
+#[derive(Debug, Clone, Default)]
+pub struct ValueCounts {
+    counts: FnvHashMap<u128, usize>,
+    num_vals: usize,
+}
+
+impl ValueCounts {
+    pub fn from_ip_addrs(ip_addrs: &[u128]) -> ValueCounts {
+        let mut counts = ValueCounts {
+            counts: FnvHashMap::with_capacity_and_hasher(ip_addrs.len() / 10, Default::default()),
+            num_vals: 0,
+        };
+        for &ip_addr in ip_addrs {
+            counts.record(ip_addr);
+        }
+        counts
+    }
+
+    pub fn record(&mut self, ip_addr: u128) {
+        *self.counts.entry(ip_addr).or_default() += 1;
+        self.num_vals += 1;
+    }
+
+    pub fn num_vals(&self) -> usize {
+        self.num_vals
+    }
+
+    pub fn num_distinct(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Same counts, with the values mapped by `to_ipv4_domain`.
+    fn to_ipv4_domain(&self) -> ValueCounts {
+        let ip_addrs: Vec<u128> = self.counts.keys().cloned().collect();
+        let counts = to_ipv4_domain(&ip_addrs)
+            .into_iter()
+            .zip(self.counts.values().cloned())
+            .collect();
+        ValueCounts {
+            counts,
+            num_vals: self.num_vals,
+        }
+    }
+
+    pub(crate) fn sorted_distinct(&self) -> Vec<u128> {
+        let mut ip_addrs: Vec<u128> = self.counts.keys().cloned().collect();
+        ip_addrs.sort_unstable();
+        ip_addrs
+    }
+
+    pub(crate) fn most_common(&self, top_n: usize) -> Vec<IPWithCount> {
+        get_most_common(&self.counts, top_n)
+    }
+}
+
+/// Default number of values per chunk of an `IpColumnWriter`.
+const DEFAULT_CHUNK_LEN: usize = 1 << 18;
+
+/// Builds a column one value at a time, and writes it as a sequence of containers
+/// that can be read back with `decode_chunked`.
+///
+/// The codecs need two passes over the values, so those are buffered, along with
+/// their number of occurrences, until `chunk_len` of them are pushed. The chunk is
+/// then encoded in its own container, trained on those counts, and the buffer is
+/// reused for the next one.
+///
+/// Memory is O(chunk_len) for the values being buffered, plus the encoded size of
+/// the chunks already written, which are held until `finish`. A column of at most
+/// `chunk_len` values is a single container, also readable with `decode_any`.
+#[derive(Debug)]
+pub struct IpColumnWriter {
+    codec: Box<dyn IpRepr>,
+    chunk_len: usize,
+    /// Values of the chunk being built.
+    ip_addrs: Vec<u128>,
+    counts: ValueCounts,
+    /// Containers of the chunks encoded so far.
+    encoded_chunks: Vec<u8>,
+    num_chunks: usize,
+    stats: CompressionStats,
+}
+
+impl IpColumnWriter {
+    pub fn new(codec: Box<dyn IpRepr>) -> IpColumnWriter {
+        IpColumnWriter {
+            codec,
+            chunk_len: DEFAULT_CHUNK_LEN,
+            ip_addrs: Vec::new(),
+            counts: ValueCounts::default(),
+            encoded_chunks: Vec::new(),
+            num_chunks: 0,
+            stats: CompressionStats::default(),
+        }
+    }
+
+    /// Sets the number of values encoded together, `1 << 18` by default.
+    ///
+    /// Panics if `chunk_len` is 0.
+    pub fn with_chunk_len(mut self, chunk_len: usize) -> IpColumnWriter {
+        assert!(chunk_len > 0, "chunks hold at least one value");
+        self.chunk_len = chunk_len;
+        self
+    }
+
+    /// Pushes a value, encoding the current chunk if it is full.
+    pub fn push(&mut self, ip_addr: u128) -> Result<(), IpReprError> {
+        self.ip_addrs.push(ip_addr);
+        self.counts.record(ip_addr);
+        if self.ip_addrs.len() == self.chunk_len {
+            self.encode_chunk()?;
+        }
+        Ok(())
+    }
+
+    /// Pushes an ip address. IPv4 addresses are mapped to IPv6.
+    pub fn push_ip(&mut self, ip_addr: IpAddr) -> Result<(), IpReprError> {
+        self.push(ip_addr_to_u128(ip_addr))
+    }
+
+    pub fn len(&self) -> usize {
+        // All of the chunks encoded before `finish` are full.
+        self.num_chunks * self.chunk_len + self.ip_addrs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Encodes the buffered values in a container, with a checksum.
+    fn encode_chunk(&mut self) -> Result<(), IpReprError> {
+        let ipv4_only = is_ipv4_only(&self.ip_addrs);
+        let (payload, stats) = if ipv4_only {
+            let counts = self.counts.to_ipv4_domain();
+            self.codec
+                .encode_with_counts(&to_ipv4_domain(&self.ip_addrs), &counts)?
+        } else {
+            self.codec
+                .encode_with_counts(&self.ip_addrs, &self.counts)?
+        };
+        write_container(
+            &*self.codec,
+            &payload,
+            self.ip_addrs.len(),
+            true,
+            ipv4_only,
+            &mut self.encoded_chunks,
+        )?;
+        self.stats.merge(&stats);
+        self.num_chunks += 1;
+        self.ip_addrs.clear();
+        self.counts = ValueCounts::default();
+        Ok(())
+    }
+
+    /// Encodes the last chunk and writes the column to `writer`.
+    ///
+    /// An empty column is written as a single empty container.
+    pub fn finish(mut self, mut writer: impl Write) -> Result<CompressionStats, IpReprError> {
+        if !self.ip_addrs.is_empty() || self.num_chunks == 0 {
+            self.encode_chunk()?;
+        }
+        writer.write_all(&self.encoded_chunks)?;
+        Ok(self.stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::{
+        decode_any, decode_chunked, encode_container, ContainerHeader, HalfDict, HalfDictQ,
+        IntervalEncoding, ZstdEncoding,
+    };
+
+    #[test]
+    fn test_column_writer() {
+        let ip_addrs: Vec<u128> = (0..5000u128)
+            .map(|i| 0xffff_0a00_0000 + (i * i * 7919) % 3000)
+            .collect();
+        let codecs: Vec<Box<dyn IpRepr>> = vec![
+            Box::new(IntervalEncoding::default()),
+            Box::new(HalfDict::new(1024, 8)),
+            Box::new(HalfDictQ::new(256)),
+            Box::new(ZstdEncoding::default()),
+        ];
+        for codec in codecs {
+            let expected = encode_container(&*codec, &ip_addrs, true).unwrap();
+            let mut column_writer = IpColumnWriter::new(codec);
+            for &ip_addr in &ip_addrs {
+                column_writer.push(ip_addr).unwrap();
+            }
+            assert_eq!(column_writer.len(), ip_addrs.len());
+            let mut output = Vec::new();
+            let stats = column_writer.finish(&mut output).unwrap();
+            assert_eq!(stats.num_vals, ip_addrs.len());
+            assert_eq!(output, expected);
+            assert_eq!(decode_any(&output).unwrap(), ip_addrs);
+        }
+    }
+
+    #[test]
+    fn test_column_writer_push_ip() {
+        let mut column_writer = IpColumnWriter::new(Box::new(IntervalEncoding::default()));
+        assert!(column_writer.is_empty());
+        column_writer
+            .push_ip(IpAddr::from_str("10.0.0.1").unwrap())
+            .unwrap();
+        column_writer
+            .push_ip(IpAddr::from_str("2001:db8::1").unwrap())
+            .unwrap();
+        let mut output = Vec::new();
+        column_writer.finish(&mut output).unwrap();
+        assert_eq!(
+            decode_any(&output).unwrap(),
+            vec![0xffff_0a00_0001, 0x2001_0db8 << 96 | 1]
+        );
+    }
+
+    #[test]
+    fn test_column_writer_chunks() {
+        // IPv4 only in the first chunks, not in the last one.
+        let mut ip_addrs: Vec<u128> = (0..5000u128)
+            .map(|i| 0xffff_0a00_0000 + (i * i * 7919) % 3000)
+            .collect();
+        ip_addrs.push(1 << 100);
+        let mut column_writer =
+            IpColumnWriter::new(Box::new(HalfDict::new(1024, 8))).with_chunk_len(1000);
+        for &ip_addr in &ip_addrs {
+            column_writer.push(ip_addr).unwrap();
+        }
+        assert_eq!(column_writer.len(), ip_addrs.len());
+        let mut output = Vec::new();
+        let stats = column_writer.finish(&mut output).unwrap();
+        assert_eq!(stats.num_vals, ip_addrs.len());
+        assert_eq!(decode_chunked(&output).unwrap(), ip_addrs);
+        let (header, _) = ContainerHeader::open(&output).unwrap();
+        assert_eq!(header.num_vals, 1000);
+        assert!(header.ipv4_only);
+        assert_eq!(decode_any(&output).unwrap(), &ip_addrs[..1000]);
+
+        let mut output = Vec::new();
+        IpColumnWriter::new(Box::new(IntervalEncoding::default()))
+            .finish(&mut output)
+            .unwrap();
+        assert_eq!(decode_chunked(&output).unwrap(), Vec::<u128>::new());
+    }
+
+    #[test]
+    fn test_value_counts() {
+        let counts = ValueCounts::from_ip_addrs(&[3, 1, 3, 2, 3, 1]);
+        assert_eq!(counts.num_vals(), 6);
+        assert_eq!(counts.num_distinct(), 3);
+        assert_eq!(counts.sorted_distinct(), vec![1, 2, 3]);
+        let most_common = counts.most_common(2);
+        assert_eq!(most_common[0].ip, 3);
+        assert_eq!(most_common[1].ip, 1);
+    }
+}
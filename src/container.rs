@@ -0,0 +1,420 @@
+use std::{hash::Hasher, io::Write};
+
+use fnv::FnvHasher;
+
+use crate::{
+    interval::{deserialize_num_vals, deserialize_vint, serialize_vint},
+    ip_addr::{from_ipv4_domain, is_ipv4_only, to_ipv4_domain},
+    DictionaryEncoding, HalfDict, HalfDictQ, IntervalEncoding, IpRepr, IpReprError,
+    RunLengthEncoding, SortedEncoding, ZstdEncoding,
+};
+
+const MAGIC: [u8; 4] = *b"IPRP";
+const FORMAT_VERSION: u8 = 1;
+const FLAG_CHECKSUM: u8 = 1;
+const FLAG_IPV4_ONLY: u8 = 2;
+
+/// Identifies the codec of an encoded column in the container header.
+///
+/// The values are part of the format and must never be reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CodecId {
+    Interval = 1,
+    HalfDict = 2,
+    HalfDictQ = 3,
+    Zstd = 4,
+    RunLength = 5,
+    Sorted = 6,
+    Dictionary = 7,
+}
+
+impl CodecId {
+    fn from_u8(code: u8) -> Result<CodecId, IpReprError> {
+        match code {
+            1 => Ok(CodecId::Interval),
+            2 => Ok(CodecId::HalfDict),
+            3 => Ok(CodecId::HalfDictQ),
+            4 => Ok(CodecId::Zstd),
+            5 => Ok(CodecId::RunLength),
+            6 => Ok(CodecId::Sorted),
+            7 => Ok(CodecId::Dictionary),
+            _ => Err(IpReprError::UnsupportedCodec),
+        }
+    }
+
+    /// Creates the codec from the parameters returned by `IpRepr::params`.
+    fn build(self, params: &[u64]) -> Result<Box<dyn IpRepr>, IpReprError> {
+        let codec: Box<dyn IpRepr> = match (self, params) {
+            (CodecId::Interval, &[add_intervall_cost_in_bits]) => {
+                Box::new(IntervalEncoding(add_intervall_cost_in_bits as usize))
+            }
+            (
+                CodecId::HalfDict,
+                &[block_size, num_bits_for_most_common, local_dicts, adaptive_dict_size],
+            ) => Box::new(
+                HalfDict::try_new(block_size as usize, num_bits_for_most_common as usize)?
+                    .with_local_dicts(local_dicts != 0)
+                    .with_adaptive_dict_size(adaptive_dict_size != 0),
+            ),
+            (CodecId::HalfDictQ, &[num_most_common, adaptive_dict_size, elias_fano_dict]) => {
+                Box::new(
+                    HalfDictQ::new(num_most_common as usize)
+                        .with_adaptive_dict_size(adaptive_dict_size != 0)
+                        .with_elias_fano_dict(elias_fano_dict != 0),
+                )
+            }
+            (CodecId::Zstd, &[level]) => Box::new(ZstdEncoding(
+                u32::try_from(level)
+                    .map_err(|_| IpReprError::InvalidParams)?
+                    .cast_signed(),
+            )),
+            (CodecId::RunLength, &[add_intervall_cost_in_bits]) => {
+                Box::new(RunLengthEncoding(add_intervall_cost_in_bits as usize))
+            }
+            (CodecId::Sorted, &[add_intervall_cost_in_bits]) => {
+                Box::new(SortedEncoding(add_intervall_cost_in_bits as usize))
+            }
+            (CodecId::Dictionary, &[add_intervall_cost_in_bits]) => {
+                Box::new(DictionaryEncoding(add_intervall_cost_in_bits as usize))
+            }
+            _ => return Err(IpReprError::InvalidParams),
+        };
+        Ok(codec)
+    }
+}
+
+/// Header of a container, as written by `encode_container`.
+///
+/// Layout:
+/// - magic bytes `IPRP`
+/// - format version
+/// - codec id
+/// - flags, bit 0 tells whether a checksum is appended to the payload, bit 1
+///   whether the values were mapped to the 32-bit IPv4 domain before encoding
+/// - codec parameters, as a vint count followed by vints
+/// - number of values
+/// - payload length
+///
+/// The header is followed by the payload of the codec and optionally by the
+/// 64 bit FNV-1a checksum of the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerHeader {
+    pub version: u8,
+    pub codec_id: CodecId,
+    pub params: Vec<u64>,
+    pub num_vals: usize,
+    pub has_checksum: bool,
+    /// All of the values are IPv4 addresses, encoded in the 32-bit domain, see
+    /// `to_ipv4_domain`.
+    pub ipv4_only: bool,
+}
+
+impl ContainerHeader {
+    fn serialize(&self, payload_len: usize, output: &mut Vec<u8>) {
+        output.extend_from_slice(&MAGIC);
+        output.push(self.version);
+        output.push(self.codec_id as u8);
+        let mut flags = 0;
+        if self.has_checksum {
+            flags |= FLAG_CHECKSUM;
+        }
+        if self.ipv4_only {
+            flags |= FLAG_IPV4_ONLY;
+        }
+        output.push(flags);
+        serialize_vint(self.params.len() as u128, output);
+        for &param in &self.params {
+            serialize_vint(param as u128, output);
+        }
+        serialize_vint(self.num_vals as u128, output);
+        serialize_vint(payload_len as u128, output);
+    }
+
+    /// Parses the header and returns it with the payload of the codec.
+    ///
+    /// The checksum, if any, is verified.
+    pub fn open(data: &[u8]) -> Result<(ContainerHeader, &[u8]), IpReprError> {
+        let (header, payload, _rest) = ContainerHeader::open_with_rest(data)?;
+        Ok((header, payload))
+    }
+
+    /// Same as `open`, also returning the data following the container.
+    fn open_with_rest(data: &[u8]) -> Result<(ContainerHeader, &[u8], &[u8]), IpReprError> {
+        if data.len() < MAGIC.len() + 3 {
+            return Err(IpReprError::TruncatedHeader);
+        }
+        if data[..MAGIC.len()] != MAGIC {
+            return Err(IpReprError::InvalidMagic);
+        }
+        let data = &data[MAGIC.len()..];
+        let version = data[0];
+        if version != FORMAT_VERSION {
+            return Err(IpReprError::UnsupportedVersion(version));
+        }
+        let codec_id = CodecId::from_u8(data[1])?;
+        let has_checksum = data[2] & FLAG_CHECKSUM != 0;
+        let ipv4_only = data[2] & FLAG_IPV4_ONLY != 0;
+        let data = &data[3..];
+
+        let (num_params, mut data) = deserialize_vint(data)?;
+        if num_params > data.len() as u128 {
+            return Err(IpReprError::TruncatedHeader);
+        }
+        let mut params = Vec::with_capacity(num_params as usize);
+        for _ in 0..num_params {
+            let (param, new_data) = deserialize_vint(data)?;
+            data = new_data;
+            params.push(u64::try_from(param).map_err(|_| IpReprError::InvalidParams)?);
+        }
+        let (num_vals, data) = deserialize_num_vals(data)?;
+        let (payload_len, data) = deserialize_vint(data)?;
+
+        let checksum_len = if has_checksum { 8 } else { 0 };
+        payload_len
+            .checked_add(checksum_len)
+            .filter(|&len| len <= data.len() as u128)
+            .ok_or(IpReprError::TruncatedData)?;
+        let (payload, data) = data.split_at(payload_len as usize);
+        let (checksum_bytes, rest) = data.split_at(checksum_len as usize);
+        if has_checksum {
+            let checksum = u64::from_le_bytes(checksum_bytes.try_into().unwrap());
+            if checksum != compute_checksum(payload) {
+                return Err(IpReprError::ChecksumMismatch);
+            }
+        }
+        let header = ContainerHeader {
+            version,
+            codec_id,
+            params,
+            num_vals,
+            has_checksum,
+            ipv4_only,
+        };
+        Ok((header, payload, rest))
+    }
+
+    /// Creates the codec the payload was encoded with.
+    pub fn codec(&self) -> Result<Box<dyn IpRepr>, IpReprError> {
+        self.codec_id.build(&self.params)
+    }
+}
+
+fn compute_checksum(payload: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(payload);
+    hasher.finish()
+}
+
+/// Encodes `ip_addrs` with `codec` and wraps the result in a self-describing
+/// container, which can be read back with `decode_any`.
+///
+/// Columns made only of IPv4 addresses are mapped to the 32-bit IPv4 domain
+/// before being encoded, see `to_ipv4_domain`.
+pub fn encode_container(
+    codec: &dyn IpRepr,
+    ip_addrs: &[u128],
+    with_checksum: bool,
+) -> Result<Vec<u8>, IpReprError> {
+    let ipv4_only = is_ipv4_only(ip_addrs);
+    let payload = if ipv4_only {
+        codec.encode(&to_ipv4_domain(ip_addrs))?
+    } else {
+        codec.encode(ip_addrs)?
+    };
+    let mut output = Vec::with_capacity(payload.len() + 32);
+    write_container(
+        codec,
+        &payload,
+        ip_addrs.len(),
+        with_checksum,
+        ipv4_only,
+        &mut output,
+    )?;
+    Ok(output)
+}
+
+/// Writes the container of a payload encoded with `codec`.
+pub(crate) fn write_container(
+    codec: &dyn IpRepr,
+    payload: &[u8],
+    num_vals: usize,
+    with_checksum: bool,
+    ipv4_only: bool,
+    writer: &mut impl Write,
+) -> Result<(), IpReprError> {
+    let header = ContainerHeader {
+        version: FORMAT_VERSION,
+        codec_id: codec.codec_id(),
+        params: codec.params(),
+        num_vals,
+        has_checksum: with_checksum,
+        ipv4_only,
+    };
+    let mut header_bytes = Vec::with_capacity(32);
+    header.serialize(payload.len(), &mut header_bytes);
+    writer.write_all(&header_bytes)?;
+    writer.write_all(payload)?;
+    if with_checksum {
+        writer.write_all(&compute_checksum(payload).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Decodes a column written by `encode_container`, whatever its codec.
+pub fn decode_any(data: &[u8]) -> Result<Vec<u128>, IpReprError> {
+    let (ip_addrs, _rest) = decode_with_rest(data)?;
+    Ok(ip_addrs)
+}
+
+/// Decodes a column written by `IpColumnWriter`, made of one or more containers,
+/// concatenating their values.
+pub fn decode_chunked(mut data: &[u8]) -> Result<Vec<u128>, IpReprError> {
+    let mut ip_addrs = Vec::new();
+    loop {
+        let (chunk, rest) = decode_with_rest(data)?;
+        ip_addrs.extend(chunk);
+        if rest.is_empty() {
+            return Ok(ip_addrs);
+        }
+        data = rest;
+    }
+}
+
+/// Decodes the container at the start of `data`, returning the data following it.
+fn decode_with_rest(data: &[u8]) -> Result<(Vec<u128>, &[u8]), IpReprError> {
+    let (header, payload, rest) = ContainerHeader::open_with_rest(data)?;
+    let mut ip_addrs = header.codec()?.decode(payload)?;
+    if ip_addrs.len() != header.num_vals {
+        return Err(IpReprError::CorruptedData);
+    }
+    if header.ipv4_only {
+        from_ipv4_domain(&mut ip_addrs)?;
+    }
+    Ok((ip_addrs, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip_addrs() -> Vec<u128> {
+        (0..3000u128)
+            .map(|i| 0xffff_c0a8_0000 + (i * 31) % 700)
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_any() {
+        let ip_addrs = ip_addrs();
+        let codecs: Vec<Box<dyn IpRepr>> = vec![
+            Box::new(IntervalEncoding(16)),
+            Box::new(HalfDict::new(512, 6)),
+            Box::new(HalfDictQ::new(100)),
+            Box::new(HalfDictQ::new(100).with_elias_fano_dict(true)),
+            Box::new(ZstdEncoding(-1)),
+            Box::new(RunLengthEncoding(16)),
+            Box::new(DictionaryEncoding(16)),
+        ];
+        for codec in codecs {
+            for with_checksum in [false, true] {
+                let data = encode_container(&*codec, &ip_addrs, with_checksum).unwrap();
+                assert_eq!(decode_any(&data).unwrap(), ip_addrs);
+                let (header, _) = ContainerHeader::open(&data).unwrap();
+                assert_eq!(header.codec_id, codec.codec_id());
+                assert_eq!(header.params, codec.params());
+                assert_eq!(header.num_vals, ip_addrs.len());
+                assert_eq!(header.has_checksum, with_checksum);
+                assert!(header.ipv4_only);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_any_sorted() {
+        let mut ip_addrs = ip_addrs();
+        ip_addrs.sort();
+        let data = encode_container(&SortedEncoding(16), &ip_addrs, true).unwrap();
+        assert_eq!(decode_any(&data).unwrap(), ip_addrs);
+    }
+
+    #[test]
+    fn test_decode_any_ipv4_only() {
+        let ip_addrs = ip_addrs();
+        let codec = IntervalEncoding::default();
+        let data = encode_container(&codec, &ip_addrs, false).unwrap();
+        let (header, payload) = ContainerHeader::open(&data).unwrap();
+        assert!(header.ipv4_only);
+        assert!(payload.len() < codec.encode(&ip_addrs).unwrap().len());
+        assert_eq!(codec.decode(payload).unwrap(), to_ipv4_domain(&ip_addrs));
+
+        let mut ip_addrs = ip_addrs;
+        ip_addrs.push(1 << 100);
+        let data = encode_container(&codec, &ip_addrs, false).unwrap();
+        let (header, _) = ContainerHeader::open(&data).unwrap();
+        assert!(!header.ipv4_only);
+        assert_eq!(decode_any(&data).unwrap(), ip_addrs);
+    }
+
+    #[test]
+    fn test_decode_any_empty() {
+        let data = encode_container(&IntervalEncoding::default(), &[], true).unwrap();
+        assert!(decode_any(&data).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_decode_any_errors() {
+        let ip_addrs = ip_addrs();
+        let mut data = encode_container(&HalfDict::new(1024, 8), &ip_addrs, true).unwrap();
+        for len in 0..data.len() {
+            assert!(decode_any(&data[..len]).is_err());
+        }
+        let last = data.len() - 9;
+        data[last] ^= 1;
+        assert_eq!(decode_any(&data), Err(IpReprError::ChecksumMismatch));
+        data[last] ^= 1;
+
+        data[0] = b'X';
+        assert_eq!(decode_any(&data), Err(IpReprError::InvalidMagic));
+        data[0] = MAGIC[0];
+        data[4] = 2;
+        assert_eq!(decode_any(&data), Err(IpReprError::UnsupportedVersion(2)));
+        data[4] = FORMAT_VERSION;
+        data[5] = 255;
+        assert_eq!(decode_any(&data), Err(IpReprError::UnsupportedCodec));
+    }
+
+    #[test]
+    fn test_build_params() {
+        assert!(CodecId::HalfDict.build(&[1024, 8, 0, 1]).is_ok());
+        assert!(CodecId::HalfDictQ.build(&[256, 1, 0]).is_ok());
+        for (codec_id, params) in [
+            (CodecId::HalfDict, &[1024, 8][..]),
+            (CodecId::HalfDict, &[1024, 8, 0]),
+            (CodecId::HalfDictQ, &[256]),
+            (CodecId::HalfDictQ, &[256, 1]),
+            (CodecId::Interval, &[]),
+            (CodecId::Zstd, &[1 << 32]),
+        ] {
+            assert!(matches!(
+                codec_id.build(params),
+                Err(IpReprError::InvalidParams)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_open_payload_len_overflow() {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&[FORMAT_VERSION, CodecId::Interval as u8, FLAG_CHECKSUM]);
+        serialize_vint(1, &mut data);
+        serialize_vint(0, &mut data);
+        serialize_vint(1, &mut data);
+        serialize_vint(u128::MAX - 3, &mut data);
+        data.extend_from_slice(&[0; 16]);
+        assert_eq!(
+            ContainerHeader::open(&data),
+            Err(IpReprError::TruncatedData)
+        );
+    }
+}
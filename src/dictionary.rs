@@ -0,0 +1,399 @@
+use std::{net::IpAddr, ops::RangeInclusive, time::Instant};
+
+use fnv::FnvHashMap;
+use tantivy_bitpacker::{compute_num_bits, BitPacker, BitUnpacker};
+
+use crate::{
+    interval::{check_bitpacked_len, deserialize_num_vals, deserialize_vint, serialize_vint},
+    u128_to_ip_addr, BitSet, Cidr, CodecId, CompressionStats, IpRepr, IpReprError, SortedEncoding,
+    SortedReader,
+};
+
+/// Encodes a low-cardinality column, e.g. `server_ip`, as a dictionary of its
+/// distinct ip addresses and the ordinal of each row in that dictionary.
+///
+/// The dictionary is sorted, so ordinals preserve the order of the ip addresses:
+/// a range of ip addresses is a range of ordinals, and rows can be grouped by
+/// ordinal without decoding them. See `DictionaryReader`.
+///
+/// The parameter is the one of `IntervalEncoding`, used for the dictionary.
+#[derive(Default, Debug)]
+pub struct DictionaryEncoding(pub usize);
+
+impl IpRepr for DictionaryEncoding {
+    fn codec_id(&self) -> CodecId {
+        CodecId::Dictionary
+    }
+
+    fn params(&self) -> Vec<u64> {
+        vec![self.0 as u64]
+    }
+
+    /// Layout:
+    /// - the length of the dictionary, followed by the distinct values encoded with
+    ///   `SortedEncoding`
+    /// - the number of values
+    /// - the number of bits per ordinal
+    /// - the ordinals, bitpacked
+    /// - 7 bytes of padding, for the `BitUnpacker`.
+    fn encode_with_stats(
+        &self,
+        ip_addrs: &[u128],
+    ) -> Result<(Vec<u8>, CompressionStats), IpReprError> {
+        let mut stats = CompressionStats::default();
+        if ip_addrs.is_empty() {
+            return Ok((Vec::new(), stats));
+        }
+        let start = Instant::now();
+        let mut terms = ip_addrs.to_vec();
+        terms.sort();
+        terms.dedup();
+        let (dict_data, dict_stats) = SortedEncoding(self.0).encode_with_stats(&terms)?;
+        let term_ords: FnvHashMap<u128, u64> = terms
+            .iter()
+            .enumerate()
+            .map(|(ord, &ip_addr)| (ip_addr, ord as u64))
+            .collect();
+        stats.train_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut output = Vec::new();
+        serialize_vint(dict_data.len() as u128, &mut output);
+        output.extend_from_slice(&dict_data);
+        serialize_vint(ip_addrs.len() as u128, &mut output);
+        let num_bits = compute_num_bits(terms.len() as u64 - 1);
+        output.push(num_bits);
+        stats.num_vals = ip_addrs.len();
+        stats.header_num_bytes = output.len();
+        stats.num_intervals = dict_stats.num_intervals;
+        stats.num_bits = dict_stats.num_bits;
+        stats.dict_size = Some(terms.len());
+        stats.dict_num_bits = Some(num_bits);
+
+        let mut bitpacker = BitPacker::default();
+        for ip_addr in ip_addrs {
+            bitpacker
+                .write(term_ords[ip_addr], num_bits, &mut output)
+                .unwrap();
+        }
+        bitpacker.close(&mut output).unwrap();
+        stats.payload_num_bytes = output.len() - stats.header_num_bytes;
+        stats.compress_time = start.elapsed();
+        Ok((output, stats))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u128>, IpReprError> {
+        let reader = DictionaryReader::open(data)?;
+        let terms: Vec<u128> = reader.terms.iter().collect();
+        Ok(reader.term_ords().map(|ord| terms[ord as usize]).collect())
+    }
+
+    fn matches_cidr(&self, data: &[u8], cidrs: &[Cidr]) -> Result<BitSet, IpReprError> {
+        let reader = DictionaryReader::open(data)?;
+        Ok(reader.matches_cidr(cidrs))
+    }
+}
+
+/// Reader over a column encoded with `DictionaryEncoding`.
+///
+/// Each row is accessed through its term ordinal, the rank of its value among the
+/// distinct values of the column. Filters on ip addresses translate into ordinal
+/// ranges once, and are then evaluated on the bitpacked ordinals.
+pub struct DictionaryReader<'a> {
+    terms: SortedReader,
+    bit_unpacker: BitUnpacker,
+    num_vals: u32,
+    data: &'a [u8],
+}
+
+impl<'a> DictionaryReader<'a> {
+    pub fn open(data: &'a [u8]) -> Result<DictionaryReader<'a>, IpReprError> {
+        if data.is_empty() {
+            return Ok(DictionaryReader {
+                terms: SortedReader::open(data)?,
+                bit_unpacker: BitUnpacker::new(0),
+                num_vals: 0,
+                data,
+            });
+        }
+        let (dict_num_bytes, data) = deserialize_vint(data)?;
+        if dict_num_bytes > data.len() as u128 {
+            return Err(IpReprError::TruncatedHeader);
+        }
+        let (dict_data, data) = data.split_at(dict_num_bytes as usize);
+        let terms = SortedReader::open(dict_data)?;
+        let (num_vals, data) = deserialize_num_vals(data)?;
+        if terms.is_empty() || num_vals == 0 {
+            return Err(IpReprError::CorruptedData);
+        }
+        let (&num_bits, data) = data.split_first().ok_or(IpReprError::TruncatedHeader)?;
+        if num_bits != compute_num_bits(terms.len() as u64 - 1) {
+            return Err(IpReprError::InvalidNumBits(num_bits));
+        }
+        check_bitpacked_len(num_vals, num_bits, data)?;
+        let reader = DictionaryReader {
+            terms,
+            bit_unpacker: BitUnpacker::new(num_bits),
+            num_vals: num_vals as u32,
+            data,
+        };
+        // Unless the ordinals span all of their bits, a corrupted one could be out of
+        // the dictionary, so the accessors do not have to check it.
+        if !reader.num_terms().is_power_of_two()
+            && reader.term_ords().any(|ord| ord >= reader.num_terms())
+        {
+            return Err(IpReprError::InvalidOrdinal);
+        }
+        Ok(reader)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.num_vals
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_vals == 0
+    }
+
+    /// Returns the number of distinct values.
+    pub fn num_terms(&self) -> u32 {
+        self.terms.len()
+    }
+
+    /// Returns the term ordinal of the value at `row`.
+    ///
+    /// Panics if `row >= self.len()`.
+    pub fn term_ord(&self, row: u32) -> u32 {
+        assert!(row < self.num_vals, "row {row} out of bounds");
+        self.bit_unpacker.get(row as u64, self.data) as u32
+    }
+
+    /// Returns the term ordinals of all of the rows.
+    pub fn term_ords(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.num_vals).map(move |row| self.bit_unpacker.get(row as u64, self.data) as u32)
+    }
+
+    /// Returns the ip address of the term ordinal `ord`.
+    ///
+    /// Panics if `ord >= self.num_terms()`.
+    pub fn ord_to_ip_addr(&self, ord: u32) -> u128 {
+        self.terms.get(ord)
+    }
+
+    /// Returns the term ordinal of `ip_addr`, if it is in the column.
+    pub fn ip_addr_to_ord(&self, ip_addr: u128) -> Option<u32> {
+        self.terms
+            .successor(ip_addr)
+            .filter(|&(_, term)| term == ip_addr)
+            .map(|(ord, _)| ord)
+    }
+
+    /// Translates a range of ip addresses into the range of the term ordinals it
+    /// covers. Returns `None` if no value of the column is within `range`.
+    pub fn ord_range(&self, range: RangeInclusive<u128>) -> Option<RangeInclusive<u32>> {
+        let (&lo, &hi) = (range.start(), range.end());
+        let ord_lo = self.terms.rank(lo);
+        let ord_hi = match hi.checked_add(1) {
+            Some(hi) => self.terms.rank(hi),
+            None => self.num_terms(),
+        };
+        (ord_lo < ord_hi).then(|| ord_lo..=ord_hi - 1)
+    }
+
+    /// Returns the value at `row`.
+    ///
+    /// Panics if `row >= self.len()`.
+    pub fn get(&self, row: u32) -> u128 {
+        self.ord_to_ip_addr(self.term_ord(row))
+    }
+
+    /// Returns the ip address at `row`, IPv4-mapped addresses being returned as IPv4.
+    ///
+    /// Panics if `row` is out of bounds.
+    pub fn get_ip(&self, row: u32) -> IpAddr {
+        u128_to_ip_addr(self.get(row))
+    }
+
+    /// Returns the rows whose term ordinal is within `ord_range`, in increasing order.
+    pub fn get_row_ids_for_ord_range(&self, ord_range: RangeInclusive<u32>) -> Vec<u32> {
+        (0..self.num_vals)
+            .filter(|&row| ord_range.contains(&self.term_ord(row)))
+            .collect()
+    }
+
+    /// Returns the rows whose value is within `range`, in increasing order.
+    pub fn get_row_ids_for_value_range(&self, range: RangeInclusive<u128>) -> Vec<u32> {
+        match self.ord_range(range) {
+            Some(ord_range) => self.get_row_ids_for_ord_range(ord_range),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the rows whose value is in any of `cidrs`, scanning the column once.
+    pub fn matches_cidr(&self, cidrs: &[Cidr]) -> BitSet {
+        let mut bitset = BitSet::with_max_value(self.num_vals);
+        let ord_ranges: Vec<RangeInclusive<u32>> = cidrs
+            .iter()
+            .flat_map(|cidr| self.ord_range(cidr.range()))
+            .collect();
+        if ord_ranges.is_empty() {
+            return bitset;
+        }
+        for (row, ord) in self.term_ords().enumerate() {
+            if ord_ranges.iter().any(|ord_range| ord_range.contains(&ord)) {
+                bitset.insert(row as u32);
+            }
+        }
+        bitset
+    }
+
+    /// Returns the number of rows of each term ordinal, i.e. a group-by count on the
+    /// values of the column.
+    pub fn count_by_ord(&self) -> Vec<u32> {
+        let mut counts = vec![0; self.num_terms() as usize];
+        for ord in self.term_ords() {
+            counts[ord as usize] += 1;
+        }
+        counts
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u128> + '_ {
+        self.term_ords().map(move |ord| self.ord_to_ip_addr(ord))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::{
+        tests::{check_random_access, check_truncated, edge_case_ip_addrs, random_vals},
+        IntervalEncoding,
+    };
+
+    /// A few hundred servers, over a couple of subnets.
+    fn server_ip_addrs(num_vals: usize) -> Vec<u128> {
+        random_vals(num_vals, 5)
+            .map(|x| {
+                let server = x % 300;
+                0xffff_0a00_0000 + (((server % 2) << 16) | (server * 37)) as u128
+            })
+            .collect()
+    }
+
+    fn test_aux_vals(ip_addrs: &[u128]) -> Vec<u8> {
+        check_random_access(&DictionaryEncoding::default(), ip_addrs, |data| {
+            let reader = DictionaryReader::open(data).unwrap();
+            (0..reader.len()).map(|row| reader.get(row)).collect()
+        })
+    }
+
+    #[test]
+    fn test_dictionary() {
+        let ip_addrs = server_ip_addrs(20_000);
+        let data = test_aux_vals(&ip_addrs);
+        let data_interval = IntervalEncoding::default().encode(&ip_addrs).unwrap();
+        assert!(data.len() < data_interval.len());
+        for ip_addrs in edge_case_ip_addrs() {
+            test_aux_vals(&ip_addrs);
+        }
+    }
+
+    #[test]
+    fn test_dictionary_stats() {
+        let ip_addrs = server_ip_addrs(5000);
+        let (data, stats) = DictionaryEncoding::default()
+            .encode_with_stats(&ip_addrs)
+            .unwrap();
+        assert_eq!(stats.num_bytes(), data.len());
+        assert_eq!(stats.dict_size, Some(300));
+        assert_eq!(stats.dict_num_bits, Some(9));
+    }
+
+    #[test]
+    fn test_dictionary_reader() {
+        let ip_addrs = [9, 0xffff_0a00_0001, 3, 9, 5, u128::MAX, 3];
+        let data = DictionaryEncoding::default().encode(&ip_addrs).unwrap();
+        let reader = DictionaryReader::open(&data).unwrap();
+        assert!(!reader.is_empty());
+        assert_eq!(reader.num_terms(), 5);
+        assert_eq!(
+            reader.term_ords().collect::<Vec<_>>(),
+            vec![2, 3, 0, 2, 1, 4, 0]
+        );
+        assert_eq!(reader.term_ord(3), 2);
+        assert_eq!(reader.ord_to_ip_addr(3), 0xffff_0a00_0001);
+        assert_eq!(reader.ip_addr_to_ord(9), Some(2));
+        assert_eq!(reader.ip_addr_to_ord(u128::MAX), Some(4));
+        assert_eq!(reader.ip_addr_to_ord(4), None);
+        assert_eq!(reader.ip_addr_to_ord(0), None);
+        assert_eq!(reader.get_ip(1), IpAddr::from_str("10.0.0.1").unwrap());
+
+        assert_eq!(reader.ord_range(4..=9), Some(1..=2));
+        assert_eq!(reader.ord_range(0..=u128::MAX), Some(0..=4));
+        assert_eq!(reader.ord_range(6..=8), None);
+        let (lo, hi) = (9, 3);
+        assert_eq!(reader.ord_range(lo..=hi), None);
+        assert_eq!(reader.get_row_ids_for_value_range(4..=9), vec![0, 3, 4]);
+        assert_eq!(reader.get_row_ids_for_ord_range(0..=0), vec![2, 6]);
+        assert!(reader.get_row_ids_for_value_range(10..=11).is_empty());
+
+        let cidrs = [
+            Cidr::from_str("10.0.0.0/8").unwrap(),
+            Cidr::from_str("::5/128").unwrap(),
+        ];
+        assert_eq!(
+            reader.matches_cidr(&cidrs).iter().collect::<Vec<_>>(),
+            vec![1, 4]
+        );
+        assert_eq!(reader.count_by_ord(), vec![2, 1, 2, 1, 1]);
+    }
+
+    #[test]
+    fn test_dictionary_range_matches_scan() {
+        let ip_addrs = server_ip_addrs(3000);
+        let data = DictionaryEncoding::default().encode(&ip_addrs).unwrap();
+        let reader = DictionaryReader::open(&data).unwrap();
+        let ranges = [
+            0xffff_0a00_0000..=0xffff_0a00_1000,
+            0xffff_0a00_0100..=0xffff_0a01_0100,
+            0xffff_0a01_0000..=u128::MAX,
+            0..=0xffff_09ff_ffff,
+        ];
+        for range in ranges {
+            let expected: Vec<u32> = (0..ip_addrs.len() as u32)
+                .filter(|&row| range.contains(&ip_addrs[row as usize]))
+                .collect();
+            assert_eq!(reader.get_row_ids_for_value_range(range), expected);
+        }
+        let counts = reader.count_by_ord();
+        for (ord, &count) in counts.iter().enumerate() {
+            let ip_addr = reader.ord_to_ip_addr(ord as u32);
+            let expected = ip_addrs.iter().filter(|&&val| val == ip_addr).count();
+            assert_eq!(count as usize, expected);
+        }
+    }
+
+    #[test]
+    fn test_dictionary_truncated() {
+        let data = DictionaryEncoding::default()
+            .encode(&server_ip_addrs(1000))
+            .unwrap();
+        check_truncated(&data, |data| DictionaryReader::open(data).is_err());
+    }
+
+    #[test]
+    fn test_dictionary_corrupted_ord() {
+        // 3 terms, so 2 bits per ordinal and ordinal 3 is out of the dictionary.
+        let mut data = DictionaryEncoding::default().encode(&[1, 2, 3, 1]).unwrap();
+        let ords_byte = data.len() - 8;
+        assert!(DictionaryReader::open(&data).is_ok());
+        data[ords_byte] = 0xff;
+        assert_eq!(
+            DictionaryReader::open(&data).err(),
+            Some(IpReprError::InvalidOrdinal)
+        );
+        assert!(DictionaryEncoding::default().decode(&data).is_err());
+    }
+}
@@ -0,0 +1,548 @@
+use std::{net::IpAddr, time::Instant};
+
+use tantivy_bitpacker::{BitPacker, BitUnpacker};
+
+use crate::{
+    interval::{
+        deserialize_num_vals, deserialize_vint, serialize_vint, train, IntervallDecompressor,
+        MAX_NUM_BITS,
+    },
+    u128_to_ip_addr, CodecId, CompressionStats, IpRepr, IpReprError,
+};
+
+/// Elias-Fano encoding of a non-decreasing sequence of `u64`.
+///
+/// Each value is split into its `num_low_bits` low bits, bitpacked, and its high
+/// bits, written in unary: the i-th value sets the bit at `high + i` of a bit
+/// vector. `n` values below `u` take about `2 + log2(u / n)` bits each.
+///
+/// Low bits are capped at `MAX_NUM_BITS`, for the `BitUnpacker`.
+pub(crate) struct EliasFano {
+    num_vals: usize,
+    num_low_bits: u8,
+    max_high: u64,
+    /// The low bits, bitpacked, followed by the padding of the `BitUnpacker`.
+    low_bits: Vec<u8>,
+    low_bits_unpacker: BitUnpacker,
+    high_bits: Vec<u64>,
+    /// Number of set bits before each word of `high_bits`.
+    word_ranks: Vec<u32>,
+}
+
+/// Returns the first index of `lo..hi` for which `pred` is false, `pred` being true
+/// then false over the range.
+fn partition_point(mut lo: usize, mut hi: usize, pred: impl Fn(usize) -> bool) -> usize {
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if pred(mid) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
+
+/// Returns the `rank`-th set bit of `word`, counting from 0.
+fn select_in_word(mut word: u64, rank: u32) -> usize {
+    for _ in 0..rank {
+        word &= word - 1;
+    }
+    word.trailing_zeros() as usize
+}
+
+impl EliasFano {
+    /// Layout:
+    /// - the number of values
+    /// - the number of low bits
+    /// - the high bits of the last value
+    /// - the low bits, bitpacked
+    /// - the words of the high bits bit vector
+    pub fn serialize(vals: &[u64], output: &mut Vec<u8>) {
+        debug_assert!(vals.windows(2).all(|pair| pair[0] <= pair[1]));
+        let num_vals = vals.len();
+        let universe = vals.last().map(|&max| max as u128 + 1).unwrap_or(0);
+        let ratio = universe / num_vals.max(1) as u128;
+        let num_low_bits = if ratio == 0 {
+            0
+        } else {
+            (127 - ratio.leading_zeros() as u8).min(MAX_NUM_BITS)
+        };
+        let max_high = vals.last().map(|&max| max >> num_low_bits).unwrap_or(0);
+        serialize_vint(num_vals as u128, output);
+        output.push(num_low_bits);
+        serialize_vint(max_high as u128, output);
+
+        let mut bitpacker = BitPacker::default();
+        let low_mask = (1u128 << num_low_bits) - 1;
+        for &val in vals {
+            bitpacker
+                .write((val as u128 & low_mask) as u64, num_low_bits, output)
+                .unwrap();
+        }
+        bitpacker.flush(output).unwrap();
+
+        let mut high_bits = vec![0u64; (num_vals + max_high as usize).div_ceil(64)];
+        for (idx, &val) in vals.iter().enumerate() {
+            let pos = (val >> num_low_bits) as usize + idx;
+            high_bits[pos / 64] |= 1 << (pos % 64);
+        }
+        for word in high_bits {
+            output.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<(EliasFano, &[u8]), IpReprError> {
+        let (num_vals, data) = deserialize_num_vals(data)?;
+        let (&num_low_bits, data) = data.split_first().ok_or(IpReprError::TruncatedHeader)?;
+        if num_low_bits > MAX_NUM_BITS {
+            return Err(IpReprError::InvalidNumBits(num_low_bits));
+        }
+        let (max_high, data) = deserialize_vint(data)?;
+        if max_high > (u64::MAX >> num_low_bits) as u128 || (num_vals == 0 && max_high > 0) {
+            return Err(IpReprError::CorruptedData);
+        }
+        let max_high = max_high as u64;
+
+        let low_bits_num_bytes = (num_vals * num_low_bits as usize).div_ceil(8);
+        let num_words = (num_vals as u128 + max_high as u128).div_ceil(64);
+        if (low_bits_num_bytes as u128 + 8 * num_words) > data.len() as u128 {
+            return Err(IpReprError::TruncatedData);
+        }
+        let (low_bits_data, data) = data.split_at(low_bits_num_bytes);
+        let mut low_bits = Vec::with_capacity(low_bits_num_bytes + 8);
+        low_bits.extend_from_slice(low_bits_data);
+        low_bits.extend_from_slice(&[0u8; 8]);
+
+        let (high_bits_data, data) = data.split_at(8 * num_words as usize);
+        let high_bits: Vec<u64> = high_bits_data
+            .chunks(8)
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        let num_high_bits = num_vals + max_high as usize;
+        let is_set = |pos: usize| high_bits[pos / 64] & (1 << (pos % 64)) != 0;
+        // The bit vector ends with the bit of the last value.
+        if num_vals > 0 && !is_set(num_high_bits - 1) {
+            return Err(IpReprError::CorruptedData);
+        }
+        if !num_high_bits.is_multiple_of(64)
+            && high_bits[num_high_bits / 64] >> (num_high_bits % 64) != 0
+        {
+            return Err(IpReprError::CorruptedData);
+        }
+        let mut word_ranks = Vec::with_capacity(high_bits.len());
+        let mut rank = 0usize;
+        for word in &high_bits {
+            word_ranks.push(rank as u32);
+            rank += word.count_ones() as usize;
+        }
+        if rank != num_vals {
+            return Err(IpReprError::CorruptedData);
+        }
+        let elias_fano = EliasFano {
+            num_vals,
+            num_low_bits,
+            max_high,
+            low_bits,
+            low_bits_unpacker: BitUnpacker::new(num_low_bits),
+            high_bits,
+            word_ranks,
+        };
+        Ok((elias_fano, data))
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_vals
+    }
+
+    fn low(&self, idx: usize) -> u64 {
+        if self.num_low_bits == 0 {
+            return 0;
+        }
+        self.low_bits_unpacker.get(idx as u64, &self.low_bits)
+    }
+
+    /// Returns the position of the `idx`-th set bit of the high bits.
+    fn select1(&self, idx: usize) -> usize {
+        let word = partition_point(0, self.word_ranks.len(), |word| {
+            self.word_ranks[word] as usize <= idx
+        }) - 1;
+        let rank_in_word = (idx - self.word_ranks[word] as usize) as u32;
+        word * 64 + select_in_word(self.high_bits[word], rank_in_word)
+    }
+
+    /// Returns the position of the `idx`-th unset bit of the high bits.
+    fn select0(&self, idx: usize) -> usize {
+        let zeros_before = |word: usize| word * 64 - self.word_ranks[word] as usize;
+        let word = partition_point(0, self.high_bits.len(), |word| zeros_before(word) <= idx) - 1;
+        let rank_in_word = (idx - zeros_before(word)) as u32;
+        word * 64 + select_in_word(!self.high_bits[word], rank_in_word)
+    }
+
+    /// Returns the number of values whose high bits are below `high`.
+    fn num_vals_below_high(&self, high: u128) -> usize {
+        if high == 0 {
+            return 0;
+        }
+        if high > self.max_high as u128 {
+            return self.num_vals;
+        }
+        // Each unset bit closes the values of a high bits value, so the values
+        // below `high` are the set bits before the `high - 1`-th unset bit.
+        let idx = high as usize - 1;
+        self.select0(idx) - idx
+    }
+
+    /// Returns the value at `idx`.
+    ///
+    /// Panics if `idx` is out of bounds.
+    pub fn get(&self, idx: usize) -> u64 {
+        assert!(idx < self.num_vals, "index {idx} out of bounds");
+        let high = (self.select1(idx) - idx) as u64;
+        (high << self.num_low_bits) | self.low(idx)
+    }
+
+    /// Returns the number of values lower than `val`.
+    pub fn rank(&self, val: u64) -> usize {
+        let high = (val >> self.num_low_bits) as u128;
+        let start = self.num_vals_below_high(high);
+        let end = self.num_vals_below_high(high + 1);
+        partition_point(start, end, |idx| self.get(idx) < val)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.high_bits
+            .iter()
+            .enumerate()
+            .flat_map(|(word_idx, &word)| {
+                let mut word = word;
+                std::iter::from_fn(move || {
+                    if word == 0 {
+                        return None;
+                    }
+                    let bit = word.trailing_zeros() as usize;
+                    word &= word - 1;
+                    Some(word_idx * 64 + bit)
+                })
+            })
+            .enumerate()
+            .map(move |(idx, pos)| (((pos - idx) as u64) << self.num_low_bits) | self.low(idx))
+    }
+}
+
+/// Encodes a sorted column, e.g. an ip keyed index or a dictionary of distinct
+/// ip addresses.
+///
+/// The values are mapped to the compact space of an `IntervalCompressor`, which
+/// preserves their order, and then Elias-Fano encoded. Values that are not
+/// sorted are rejected with `IpReprError::UnsortedInput`.
+///
+/// The parameter is the one of `IntervalEncoding`.
+#[derive(Default, Debug)]
+pub struct SortedEncoding(pub usize);
+
+impl IpRepr for SortedEncoding {
+    fn codec_id(&self) -> CodecId {
+        CodecId::Sorted
+    }
+
+    fn params(&self) -> Vec<u64> {
+        vec![self.0 as u64]
+    }
+
+    /// Layout:
+    /// - the header of the `IntervalCompressor`
+    /// - the compact values, Elias-Fano encoded
+    fn encode_with_stats(
+        &self,
+        ip_addrs: &[u128],
+    ) -> Result<(Vec<u8>, CompressionStats), IpReprError> {
+        let mut stats = CompressionStats::default();
+        if ip_addrs.is_empty() {
+            return Ok((Vec::new(), stats));
+        }
+        if ip_addrs.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(IpReprError::UnsortedInput);
+        }
+        let start = Instant::now();
+        let compressor = train(ip_addrs, self.0);
+        stats.train_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut output = Vec::new();
+        compressor.write_header(&mut output);
+        stats.num_vals = ip_addrs.len();
+        stats.header_num_bytes = output.len();
+        stats.num_intervals = Some(compressor.num_intervals());
+        stats.num_bits = Some(compressor.num_bits);
+        let compacts: Vec<u64> = ip_addrs
+            .iter()
+            .map(|&ip_addr| compressor.to_compact(ip_addr))
+            .collect();
+        EliasFano::serialize(&compacts, &mut output);
+        stats.payload_num_bytes = output.len() - stats.header_num_bytes;
+        stats.compress_time = start.elapsed();
+        Ok((output, stats))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u128>, IpReprError> {
+        let reader = SortedReader::open(data)?;
+        Ok(reader.iter().collect())
+    }
+}
+
+/// Writes a sorted list of distinct ip addresses with `SortedEncoding`, prefixed by
+/// its length.
+pub(crate) fn serialize_sorted_ips_elias_fano(
+    ip_addrs_sorted: &[u128],
+    output: &mut Vec<u8>,
+) -> Result<(), IpReprError> {
+    let data = SortedEncoding::default().encode(ip_addrs_sorted)?;
+    serialize_vint(data.len() as u128, output);
+    output.extend_from_slice(&data);
+    Ok(())
+}
+
+/// Reads a list written by `serialize_sorted_ips_elias_fano`.
+pub(crate) fn deserialize_sorted_ips_elias_fano(
+    data: &[u8],
+) -> Result<(Vec<u128>, &[u8]), IpReprError> {
+    let (num_bytes, data) = deserialize_vint(data)?;
+    if num_bytes > data.len() as u128 {
+        return Err(IpReprError::TruncatedData);
+    }
+    let (sorted_data, data) = data.split_at(num_bytes as usize);
+    let ip_addrs = SortedReader::open(sorted_data)?.iter().collect();
+    Ok((ip_addrs, data))
+}
+
+/// Reader over a column encoded with `SortedEncoding`.
+///
+/// Values are fetched, and ranked, in O(log n).
+pub struct SortedReader {
+    decompressor: IntervallDecompressor,
+    elias_fano: Option<EliasFano>,
+}
+
+impl SortedReader {
+    pub fn open(data: &[u8]) -> Result<SortedReader, IpReprError> {
+        if data.is_empty() {
+            return Ok(SortedReader {
+                decompressor: IntervallDecompressor::default(),
+                elias_fano: None,
+            });
+        }
+        let (decompressor, data) = IntervallDecompressor::open(data)?;
+        let (elias_fano, _) = EliasFano::deserialize(data)?;
+        // Values are sorted, so checking the last one checks all of them.
+        if let Some(last) = elias_fano.len().checked_sub(1) {
+            decompressor.checked_compact_to_ip_addr(elias_fano.get(last))?;
+        }
+        Ok(SortedReader {
+            decompressor,
+            elias_fano: Some(elias_fano),
+        })
+    }
+
+    pub fn len(&self) -> u32 {
+        self.elias_fano
+            .as_ref()
+            .map(|elias_fano| elias_fano.len() as u32)
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the value at `row`.
+    ///
+    /// Panics if `row >= self.len()`.
+    pub fn get(&self, row: u32) -> u128 {
+        assert!(row < self.len(), "row {row} out of bounds");
+        let compact = self.elias_fano.as_ref().unwrap().get(row as usize);
+        self.decompressor.compact_to_ip_addr(compact)
+    }
+
+    /// Returns the ip address at `row`, IPv4-mapped addresses being returned as IPv4.
+    ///
+    /// Panics if `row` is out of bounds.
+    pub fn get_ip(&self, row: u32) -> IpAddr {
+        u128_to_ip_addr(self.get(row))
+    }
+
+    /// Returns the number of values lower than `ip_addr`.
+    pub fn rank(&self, ip_addr: u128) -> u32 {
+        let Some(elias_fano) = &self.elias_fano else {
+            return 0;
+        };
+        // The smallest compact value mapping to an ip address >= `ip_addr`.
+        match self.decompressor.compact_range(ip_addr..=u128::MAX) {
+            Some(compact_range) => elias_fano.rank(*compact_range.start()) as u32,
+            None => self.len(),
+        }
+    }
+
+    /// Returns the first row whose value is greater than or equal to `ip_addr`,
+    /// with its value.
+    pub fn successor(&self, ip_addr: u128) -> Option<(u32, u128)> {
+        let row = self.rank(ip_addr);
+        (row < self.len()).then(|| (row, self.get(row)))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u128> + '_ {
+        self.elias_fano
+            .iter()
+            .flat_map(|elias_fano| elias_fano.iter())
+            .map(|compact| self.decompressor.compact_to_ip_addr(compact))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::random_vals, IntervalEncoding};
+
+    fn elias_fano(vals: &[u64]) -> EliasFano {
+        let mut data = Vec::new();
+        EliasFano::serialize(vals, &mut data);
+        let (elias_fano, remaining) = EliasFano::deserialize(&data).unwrap();
+        assert!(remaining.is_empty());
+        for len in 0..data.len() {
+            assert!(EliasFano::deserialize(&data[..len]).is_err());
+        }
+        elias_fano
+    }
+
+    #[test]
+    fn test_elias_fano() {
+        let mut random_sorted: Vec<u64> = random_vals(5000, 1).map(|val| val % 1_000_000).collect();
+        random_sorted.sort();
+        for vals in [
+            vec![],
+            vec![0],
+            vec![u64::MAX],
+            vec![0, 0, 0, 5, 5, u64::MAX, u64::MAX],
+            (0..1000).collect(),
+            (0..1000).map(|val| val * 1_000_003).collect(),
+            random_sorted,
+        ] {
+            let elias_fano = elias_fano(&vals);
+            assert_eq!(elias_fano.len(), vals.len());
+            assert_eq!(elias_fano.iter().collect::<Vec<_>>(), vals);
+            for (idx, &val) in vals.iter().enumerate() {
+                assert_eq!(elias_fano.get(idx), val);
+            }
+            let mut probes: Vec<u64> = vals
+                .iter()
+                .flat_map(|&val| [val.saturating_sub(1), val, val.saturating_add(1)])
+                .collect();
+            probes.extend([0, u64::MAX]);
+            for probe in probes {
+                let expected = vals.partition_point(|&val| val < probe);
+                assert_eq!(elias_fano.rank(probe), expected, "{probe}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_elias_fano_num_bits() {
+        // 1000 values spread over 2^20 take about 2 + 10 bits each.
+        let vals: Vec<u64> = (0..1000).map(|val| val * 1024 + val % 7).collect();
+        let mut data = Vec::new();
+        EliasFano::serialize(&vals, &mut data);
+        assert!(data.len() * 8 < 1000 * 13);
+    }
+
+    #[test]
+    fn test_elias_fano_max_high_out_of_range() {
+        // `max_high << num_low_bits` would wrap around to 0 in u128.
+        for (num_low_bits, max_high) in [(57, 1u128 << 71), (0, 1 << 64), (8, 1 << 56)] {
+            let mut data = Vec::new();
+            serialize_vint(1, &mut data);
+            data.push(num_low_bits);
+            serialize_vint(max_high, &mut data);
+            data.extend_from_slice(&[0xff; 64]);
+            assert_eq!(
+                EliasFano::deserialize(&data).err(),
+                Some(IpReprError::CorruptedData)
+            );
+        }
+    }
+
+    fn sorted_ip_addrs(num_vals: usize) -> Vec<u128> {
+        let mut ip_addrs: Vec<u128> = random_vals(num_vals, 5)
+            .map(|random| {
+                if random % 10 == 0 {
+                    0x2001_0db8 << 96 | (random >> 8) as u128
+                } else {
+                    0xffff_0000_0000 + (random % 100_000) as u128
+                }
+            })
+            .collect();
+        ip_addrs.sort();
+        ip_addrs
+    }
+
+    #[test]
+    fn test_sorted_encoding() {
+        let ip_addrs = sorted_ip_addrs(10_000);
+        let codec = SortedEncoding::default();
+        let (data, stats) = codec.encode_with_stats(&ip_addrs).unwrap();
+        assert_eq!(stats.num_bytes(), data.len());
+        assert_eq!(codec.decode(&data).unwrap(), ip_addrs);
+        let data_interval = IntervalEncoding::default().encode(&ip_addrs).unwrap();
+        assert!(data.len() < data_interval.len());
+        assert!(codec
+            .decode(&codec.encode(&[]).unwrap())
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            codec
+                .decode(&codec.encode(&[u128::MAX; 3]).unwrap())
+                .unwrap(),
+            [u128::MAX; 3]
+        );
+        assert_eq!(codec.encode(&[2, 1]), Err(IpReprError::UnsortedInput));
+    }
+
+    #[test]
+    fn test_sorted_reader() {
+        let ip_addrs = sorted_ip_addrs(2000);
+        let data = SortedEncoding::default().encode(&ip_addrs).unwrap();
+        let reader = SortedReader::open(&data).unwrap();
+        assert_eq!(reader.len(), 2000);
+        assert!(!reader.is_empty());
+        for (row, &ip_addr) in ip_addrs.iter().enumerate() {
+            assert_eq!(reader.get(row as u32), ip_addr);
+        }
+        let mut probes: Vec<u128> = ip_addrs
+            .iter()
+            .flat_map(|&ip_addr| [ip_addr - 1, ip_addr, ip_addr + 1])
+            .collect();
+        probes.extend([0, u128::MAX, 0xffff_0000_0000 + 50_000, 0x2001_0db8 << 96]);
+        for probe in probes {
+            let row = ip_addrs.partition_point(|&ip_addr| ip_addr < probe);
+            assert_eq!(reader.rank(probe), row as u32);
+            let expected = ip_addrs.get(row).map(|&ip_addr| (row as u32, ip_addr));
+            assert_eq!(reader.successor(probe), expected);
+        }
+        let empty = SortedReader::open(&[]).unwrap();
+        assert_eq!(empty.rank(5), 0);
+        assert_eq!(empty.successor(5), None);
+    }
+
+    #[test]
+    fn test_sorted_ips_elias_fano() {
+        let ip_addrs = sorted_ip_addrs(500);
+        let mut data = Vec::new();
+        serialize_sorted_ips_elias_fano(&ip_addrs, &mut data).unwrap();
+        data.push(42);
+        let (deserialized, remaining) = deserialize_sorted_ips_elias_fano(&data).unwrap();
+        assert_eq!(deserialized, ip_addrs);
+        assert_eq!(remaining, &[42]);
+        for len in 0..data.len() - 1 {
+            assert!(deserialize_sorted_ips_elias_fano(&data[..len]).is_err());
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::{fmt, io};
+
+/// Error returned by the `IpRepr` codecs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IpReprError {
+    /// The data ended before the header could be read entirely.
+    TruncatedHeader,
+    /// The data ended before all of the encoded values could be read.
+    TruncatedData,
+    /// A variable length integer does not fit in a `u128`.
+    InvalidVInt,
+    /// The number of bits per value is out of range, e.g. greater than 64.
+    InvalidNumBits(u8),
+    /// The number of values is larger than what a column can hold.
+    InvalidNumVals,
+    /// The encoded values are inconsistent.
+    CorruptedData,
+    /// A value cannot be represented by the codec.
+    ValueOutOfRange(u128),
+    /// A dictionary ordinal points outside of the dictionary.
+    InvalidOrdinal,
+    /// The interval table is not strictly increasing, or maps values
+    /// outside of the `u128` domain.
+    NonMonotonicIntervals,
+    /// The data does not start with the container magic bytes.
+    InvalidMagic,
+    /// The container was written with an unknown format version.
+    UnsupportedVersion(u8),
+    /// The checksum of the container does not match its payload.
+    ChecksumMismatch,
+    /// The parameters of a codec are out of range.
+    InvalidParams,
+    /// The string is not a valid ip prefix.
+    InvalidCidr(String),
+    /// The codec does not support this operation.
+    UnsupportedCodec,
+    /// The codec requires sorted values.
+    UnsortedInput,
+    /// Writing the encoded column failed.
+    Io(io::ErrorKind),
+}
+
+impl fmt::Display for IpReprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpReprError::TruncatedHeader => write!(f, "truncated header"),
+            IpReprError::TruncatedData => write!(f, "truncated data"),
+            IpReprError::InvalidVInt => write!(f, "invalid vint"),
+            IpReprError::InvalidNumBits(num_bits) => {
+                write!(f, "invalid number of bits: {num_bits}")
+            }
+            IpReprError::InvalidNumVals => write!(f, "invalid number of values"),
+            IpReprError::CorruptedData => write!(f, "corrupted data"),
+            IpReprError::ValueOutOfRange(val) => write!(f, "value out of range: {val}"),
+            IpReprError::InvalidOrdinal => write!(f, "invalid dictionary ordinal"),
+            IpReprError::NonMonotonicIntervals => write!(f, "interval table is not monotonic"),
+            IpReprError::InvalidMagic => write!(f, "invalid magic bytes"),
+            IpReprError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version: {version}")
+            }
+            IpReprError::ChecksumMismatch => write!(f, "checksum mismatch"),
+            IpReprError::InvalidParams => write!(f, "invalid codec parameters"),
+            IpReprError::InvalidCidr(cidr) => write!(f, "invalid cidr: {cidr}"),
+            IpReprError::UnsupportedCodec => write!(f, "unsupported codec"),
+            IpReprError::UnsortedInput => write!(f, "values are not sorted"),
+            IpReprError::Io(kind) => write!(f, "io error: {kind}"),
+        }
+    }
+}
+
+impl std::error::Error for IpReprError {}
+
+impl From<io::Error> for IpReprError {
+    fn from(err: io::Error) -> Self {
+        IpReprError::Io(err.kind())
+    }
+}
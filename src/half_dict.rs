@@ -2,25 +2,41 @@ use std::{fmt::Debug, time::Instant};
 
 use fnv::FnvHashMap;
 use itertools::Itertools;
+use tantivy_bitpacker::{compute_num_bits, BitPacker, BitUnpacker};
 
 use crate::{
+    elias_fano::EliasFano,
     get_most_common,
-    interval::{train, IntervalCompressor},
-    IpRepr,
+    heavy_hitters::HeavyHitters,
+    interval::{
+        check_bitpacked_len, deserialize_num_vals, deserialize_sorted_ips, deserialize_vint,
+        serialize_sorted_ips, serialize_vint, train_on_distinct, train_on_sample, train_streaming,
+        IntervalCompressor, IntervallDecompressor,
+    },
+    CodecId, CompressionStats, IPWithCount, IpRepr, IpReprError, ValueCounts,
 };
 
-//struct Block {
-//// The bit_mask that marks if an element is coming from the dictionary
-//bit_mask: BlockBitMask,
-//// the non-dictionary data, compressed as vint in compressed space
-//residual_data: Vec<u8>,
-//}
+// struct Block {
+//     // The bit_mask that marks if an element is coming from the dictionary
+//     bit_mask: BlockBitMask,
+//     // the non-dictionary data, compressed as vint in compressed space
+//     residual_data: Vec<u8>,
+// }
 
-/// Bit mask of size 1024 (0-1023)
-#[derive(Default)]
+const MAX_BLOCK_SIZE: usize = 1 << 16;
+const MAX_NUM_BITS_FOR_MOST_COMMON: usize = 16;
+
+/// One bit per position of the block, written as is.
+const PRESENCE_BIT_MASK: u8 = 0;
+/// The sorted positions of the set bits, bitpacked.
+const PRESENCE_POSITIONS: u8 = 1;
+/// The lengths of the runs of unset then set bits, alternating, bitpacked.
+const PRESENCE_RUNS: u8 = 2;
+
+/// Bit mask over the positions of a block.
 struct BlockBitMask {
     // The bit_mask that marks if an element is coming from the dictionary
-    bit_mask: [u64; 16], // 1024 bits
+    bit_mask: Vec<u64>,
 }
 
 impl Debug for BlockBitMask {
@@ -38,44 +54,337 @@ impl Debug for BlockBitMask {
 }
 
 impl BlockBitMask {
+    fn with_len(block_len: usize) -> BlockBitMask {
+        BlockBitMask {
+            bit_mask: vec![0u64; num_words(block_len)],
+        }
+    }
+
     fn set(&mut self, pos: usize) {
         let byte = pos / 64;
         let bit = pos % 64;
         self.bit_mask[byte] |= 1 << bit;
     }
+
+    fn is_set(&self, pos: usize) -> bool {
+        let byte = pos / 64;
+        let bit = pos % 64;
+        self.bit_mask[byte] & (1 << bit) != 0
+    }
+
+    /// Writes the words covering the first `block_len` bits.
+    fn serialize(&self, block_len: usize, output: &mut Vec<u8>) {
+        for word in &self.bit_mask[..num_words(block_len)] {
+            output.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    fn deserialize(block_len: usize, data: &[u8]) -> Result<(BlockBitMask, &[u8]), IpReprError> {
+        let num_bytes = num_words(block_len) * 8;
+        if data.len() < num_bytes {
+            return Err(IpReprError::TruncatedData);
+        }
+        let mut bit_mask = BlockBitMask::with_len(block_len);
+        for (word, bytes) in bit_mask
+            .bit_mask
+            .iter_mut()
+            .zip(data[..num_bytes].chunks(8))
+        {
+            *word = u64::from_le_bytes(bytes.try_into().unwrap());
+        }
+        // Ignore the bits past the end of the block.
+        if !block_len.is_multiple_of(64) {
+            bit_mask.bit_mask[block_len / 64] &= (1u64 << (block_len % 64)) - 1;
+        }
+        Ok((bit_mask, &data[num_bytes..]))
+    }
+
+    /// Writes the kind of presence encoding, followed by the smallest of the bit
+    /// mask, the list of positions and the run lengths.
+    fn serialize_cheapest(&self, block_len: usize, output: &mut Vec<u8>) {
+        let positions: Vec<u64> = (0..block_len)
+            .filter(|&pos| self.is_set(pos))
+            .map(|pos| pos as u64)
+            .collect();
+        let mut positions_data = Vec::new();
+        serialize_bitpacked(
+            &positions,
+            compute_num_bits(block_len as u64 - 1),
+            &mut positions_data,
+        );
+
+        let mut runs = Vec::new();
+        let mut run_start = 0;
+        let mut is_set = false;
+        for pos in 0..block_len {
+            if self.is_set(pos) != is_set {
+                runs.push((pos - run_start) as u64);
+                run_start = pos;
+                is_set = !is_set;
+            }
+        }
+        runs.push((block_len - run_start) as u64);
+        let mut runs_data = Vec::new();
+        serialize_bitpacked(&runs, compute_num_bits(block_len as u64), &mut runs_data);
+
+        let bit_mask_len = num_words(block_len) * 8;
+        if positions_data.len() <= runs_data.len() && positions_data.len() < bit_mask_len {
+            output.push(PRESENCE_POSITIONS);
+            output.extend_from_slice(&positions_data);
+        } else if runs_data.len() < bit_mask_len {
+            output.push(PRESENCE_RUNS);
+            output.extend_from_slice(&runs_data);
+        } else {
+            output.push(PRESENCE_BIT_MASK);
+            self.serialize(block_len, output);
+        }
+    }
+
+    /// Reads a bit mask written by `serialize_cheapest`.
+    fn deserialize_any(
+        block_len: usize,
+        data: &[u8],
+    ) -> Result<(BlockBitMask, &[u8]), IpReprError> {
+        let (&presence_kind, data) = data.split_first().ok_or(IpReprError::TruncatedData)?;
+        match presence_kind {
+            PRESENCE_BIT_MASK => BlockBitMask::deserialize(block_len, data),
+            PRESENCE_POSITIONS => {
+                let mut bit_mask = BlockBitMask::with_len(block_len);
+                let mut next_pos = 0;
+                let data = deserialize_bitpacked(
+                    block_len,
+                    compute_num_bits(block_len as u64 - 1),
+                    data,
+                    |pos| {
+                        let pos = pos as usize;
+                        if pos < next_pos || pos >= block_len {
+                            return Err(IpReprError::CorruptedData);
+                        }
+                        bit_mask.set(pos);
+                        next_pos = pos + 1;
+                        Ok(())
+                    },
+                )?;
+                Ok((bit_mask, data))
+            }
+            PRESENCE_RUNS => {
+                let mut bit_mask = BlockBitMask::with_len(block_len);
+                let mut pos = 0;
+                let mut is_set = false;
+                let data = deserialize_bitpacked(
+                    block_len + 1,
+                    compute_num_bits(block_len as u64),
+                    data,
+                    |run_len| {
+                        let run_end = pos + run_len as usize;
+                        if run_end > block_len {
+                            return Err(IpReprError::CorruptedData);
+                        }
+                        if is_set {
+                            (pos..run_end).for_each(|pos| bit_mask.set(pos));
+                        }
+                        pos = run_end;
+                        is_set = !is_set;
+                        Ok(())
+                    },
+                )?;
+                if pos != block_len {
+                    return Err(IpReprError::CorruptedData);
+                }
+                Ok((bit_mask, data))
+            }
+            _ => Err(IpReprError::CorruptedData),
+        }
+    }
+
+    fn count_ones(&self) -> usize {
+        self.bit_mask
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+}
+
+fn num_words(block_len: usize) -> usize {
+    block_len.div_ceil(64)
+}
+
+/// Writes the number of values, followed by the values bitpacked.
+fn serialize_bitpacked(vals: &[u64], num_bits: u8, output: &mut Vec<u8>) {
+    serialize_vint(vals.len() as u128, output);
+    let mut bitpacker = BitPacker::default();
+    for &val in vals {
+        bitpacker.write(val, num_bits, output).unwrap();
+    }
+    bitpacker.flush(output).unwrap();
+}
+
+/// Reads values written by `serialize_bitpacked`, at most `max_num_vals`.
+fn deserialize_bitpacked(
+    max_num_vals: usize,
+    num_bits: u8,
+    data: &[u8],
+    visitor: impl FnMut(u64) -> Result<(), IpReprError>,
+) -> Result<&[u8], IpReprError> {
+    let (num_vals, data) = deserialize_vint(data)?;
+    if num_vals > max_num_vals as u128 {
+        return Err(IpReprError::CorruptedData);
+    }
+    read_bitpacked(num_vals as usize, num_bits, data, visitor)
 }
 
 #[test]
 fn bit_mask_test() {
-    let mut bit_mask = BlockBitMask::default();
+    let mut bit_mask = BlockBitMask::with_len(1024);
 
     bit_mask.set(0);
     bit_mask.set(1023);
     bit_mask.set(960);
-    dbg!(bit_mask);
+    assert!(bit_mask.is_set(960));
+    assert!(!bit_mask.is_set(961));
+    assert_eq!(bit_mask.count_ones(), 3);
 }
 
 #[derive(Debug)]
 pub struct HalfDict {
     block_size: usize,
     num_bits_for_most_common: usize,
+    max_counters: Option<usize>,
+    local_dicts: bool,
+    adaptive_dict_size: bool,
 }
 
 impl HalfDict {
+    /// Creates a `HalfDict` codec.
+    ///
+    /// The `2^num_bits_for_most_common` most common ip addresses are dictionary encoded,
+    /// the others are bitpacked in the compact space of an `IntervalCompressor`.
+    ///
+    /// Panics if `block_size` is not in `1..=65536` or if `num_bits_for_most_common`
+    /// is greater than 16.
     pub fn new(block_size: usize, num_bits_for_most_common: usize) -> HalfDict {
-        HalfDict {
+        HalfDict::try_new(block_size, num_bits_for_most_common).unwrap()
+    }
+
+    /// Same as `new`, but returns `IpReprError::InvalidParams` instead of panicking.
+    pub fn try_new(
+        block_size: usize,
+        num_bits_for_most_common: usize,
+    ) -> Result<HalfDict, IpReprError> {
+        if block_size == 0
+            || block_size > MAX_BLOCK_SIZE
+            || num_bits_for_most_common > MAX_NUM_BITS_FOR_MOST_COMMON
+        {
+            return Err(IpReprError::InvalidParams);
+        }
+        Ok(HalfDict {
             block_size,
             num_bits_for_most_common,
+            max_counters: None,
+            local_dicts: false,
+            adaptive_dict_size: false,
+        })
+    }
+
+    /// Selects the dictionary with the Space-Saving algorithm, tracking at most
+    /// `max_counters` values instead of counting all of the distinct values.
+    ///
+    /// At least as many counters as dictionary entries are used.
+    pub fn with_max_counters(mut self, max_counters: usize) -> HalfDict {
+        self.max_counters = Some(max_counters);
+        self
+    }
+
+    /// Lets each block pick the cheapest of the global dictionary, a dictionary of
+    /// its own most common values, or no dictionary at all.
+    ///
+    /// This pays off when the most common values change over the column, as with
+    /// logs where the hot ip addresses differ from a block to the next.
+    pub fn with_local_dicts(mut self, local_dicts: bool) -> HalfDict {
+        self.local_dicts = local_dicts;
+        self
+    }
+
+    /// Picks the size of the dictionary from the frequencies of the values, see
+    /// `choose_dict_size`. `num_bits_for_most_common` becomes an upper bound.
+    pub fn with_adaptive_dict_size(mut self, adaptive_dict_size: bool) -> HalfDict {
+        self.adaptive_dict_size = adaptive_dict_size;
+        self
+    }
+}
+
+/// Size of a dictionary, as chosen by `choose_dict_size`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DictSize {
+    num_bits: usize,
+    num_entries: usize,
+}
+
+/// Returns the dictionary size minimizing the encoded size, with
+/// `top_ips_ordered` the most common values, most common first.
+///
+/// For each number of bits per ordinal, the frequency curve is walked until the
+/// marginal entry no longer saves bits: its occurrences save
+/// `num_bits_other - num_bits` bits each, while the entry costs a delta in the
+/// header, of about `num_bits_other - num_bits` bits for `2^num_bits` entries
+/// spread over the compact space. The number of bits saving the most wins, the
+/// smallest on ties.
+fn choose_dict_size(
+    top_ips_ordered: &[IPWithCount],
+    num_bits_other: u8,
+    max_num_bits: usize,
+) -> DictSize {
+    let mut best = DictSize {
+        num_bits: 0,
+        num_entries: 0,
+    };
+    let mut best_num_bits_saved = 0;
+    for num_bits in 0..=max_num_bits {
+        let saving_per_val = (num_bits_other as usize).saturating_sub(num_bits);
+        // Deltas are encoded as vints, of 7 bits per byte.
+        let entry_num_bits = (saving_per_val / 7 + 1) * 8;
+        let mut num_bits_saved = 0;
+        let mut num_entries = 0;
+        for entry in top_ips_ordered.iter().take(1 << num_bits) {
+            let entry_saving = entry.count * saving_per_val;
+            if entry_saving <= entry_num_bits {
+                break;
+            }
+            num_bits_saved += entry_saving - entry_num_bits;
+            num_entries += 1;
+        }
+        if num_bits_saved > best_num_bits_saved {
+            best_num_bits_saved = num_bits_saved;
+            best = DictSize {
+                num_bits,
+                num_entries,
+            };
         }
     }
+    best
 }
 
+/// Set on the byte holding `num_bits_for_most_common` when each block starts with
+/// the kind of its encoding.
+const LOCAL_DICTS_FLAG: u8 = 0x80;
+/// Set on the byte holding `num_bits_for_most_common` when the dictionary is
+/// Elias-Fano encoded in compact space, rather than delta encoded.
+const ELIAS_FANO_DICT_FLAG: u8 = 0x20;
+
+/// Values found in the global dictionary are replaced by their ordinal.
+const GLOBAL_DICT_BLOCK: u8 = 0;
+/// Values found in the dictionary of the block are replaced by their ordinal.
+const LOCAL_DICT_BLOCK: u8 = 1;
+/// All of the values are bitpacked in compact space.
+const PLAIN_BLOCK: u8 = 2;
+
 struct HalfDictCompressor {
     interval_compressor: IntervalCompressor,
-    //top_ips_ordered: Vec<IPWithCount>,
+    top_ips_sorted: Vec<u128>,
     ip_to_ordinal: FnvHashMap<u128, u16>,
     block_size: usize,
     num_bits_for_most_common: usize,
+    local_dicts: bool,
 }
 
 struct BlockMetaData {
@@ -83,6 +392,8 @@ struct BlockMetaData {
     block_size: usize,
     num_bits_other: u8,
     num_dict_encoded: usize,
+    /// Size of the dictionary stored in the block, if any.
+    dict_num_bits: usize,
 }
 
 impl BlockMetaData {
@@ -90,109 +401,854 @@ impl BlockMetaData {
         let dict_enc_bits = self.num_dict_encoded * self.num_bits_for_dict_encoded;
         let other_enc_size =
             (self.block_size - self.num_dict_encoded) * self.num_bits_other as usize;
-        let bit_mask_num_bits = self.block_size;
-        dict_enc_bits + other_enc_size + bit_mask_num_bits
+        dict_enc_bits + other_enc_size + self.presence_num_bits() + self.dict_num_bits
+    }
+
+    /// Size of the bit mask, or of the positions of the dictionary encoded values
+    /// when smaller. Runs are not accounted for.
+    fn presence_num_bits(&self) -> usize {
+        let positions_num_bits =
+            self.num_dict_encoded * compute_num_bits(self.block_size as u64 - 1) as usize;
+        self.block_size.min(positions_num_bits)
     }
     fn _num_bits_per_elem(&self) -> f32 {
         self.get_num_bits() as f32 / self.block_size as f32
     }
 }
 
+/// Dictionary of the most common values of a block.
+///
+/// Its entries are references to the global dictionary, followed by values in
+/// compact space. The ordinal of an entry is its position.
+struct LocalDict {
+    global_ordinals: Vec<u16>,
+    compacts: Vec<u64>,
+    ip_to_ordinal: FnvHashMap<u128, u16>,
+    num_bits: u8,
+}
+
+enum BlockEncoding {
+    GlobalDict,
+    LocalDict(LocalDict),
+    Plain,
+}
+
+/// Layout:
+/// - the header of the `IntervalCompressor`
+/// - block size and num bits for the dictionary ordinals, with `LOCAL_DICTS_FLAG`
+///   and `ELIAS_FANO_DICT_FLAG`
+/// - the number of values
+/// - the dictionary, sorted. The ordinal of an entry is its position. It is
+///   delta encoded, or Elias-Fano encoded in compact space when smaller.
+/// - for each block, including the trailing partial block:
+///   - with `LOCAL_DICTS_FLAG` only, the kind of encoding of the block.
+///     `LOCAL_DICT_BLOCK` is followed by its number of global references and of
+///     compact values, then those, bitpacked.
+///   - unless it is a `PLAIN_BLOCK`, the bit mask, as written by
+///     `BlockBitMask::serialize_cheapest`, and the bitpacked ordinals
+///   - the bitpacked residuals in compact space.
+/// - 7 bytes of padding, for the `BitUnpacker`.
 impl HalfDictCompressor {
-    fn compress(&self, ip_addrs: &[u128]) -> Vec<u8> {
-        let mut _num_blocks = ip_addrs.len() / self.block_size;
-        if ip_addrs.len() % self.block_size != 0 {
-            _num_blocks += 1;
+    fn compress(&self, ip_addrs: &[u128], stats: &mut CompressionStats) -> Vec<u8> {
+        let mut output = Vec::new();
+        self.interval_compressor.write_header(&mut output);
+        serialize_vint(self.block_size as u128, &mut output);
+        let mut num_bits_byte = self.num_bits_for_most_common as u8;
+        if self.local_dicts {
+            num_bits_byte |= LOCAL_DICTS_FLAG;
         }
-        let iter = ip_addrs.chunks_exact(self.block_size);
+        let mut dict_data = Vec::new();
+        serialize_sorted_ips(&self.top_ips_sorted, &mut dict_data);
+        let compacts: Vec<u64> = self
+            .top_ips_sorted
+            .iter()
+            .map(|&ip_addr| self.interval_compressor.to_compact(ip_addr))
+            .collect();
+        let mut elias_fano_dict_data = Vec::new();
+        EliasFano::serialize(&compacts, &mut elias_fano_dict_data);
+        if elias_fano_dict_data.len() < dict_data.len() {
+            num_bits_byte |= ELIAS_FANO_DICT_FLAG;
+            dict_data = elias_fano_dict_data;
+        }
+        output.push(num_bits_byte);
+        serialize_vint(ip_addrs.len() as u128, &mut output);
+        output.extend_from_slice(&dict_data);
+        stats.num_vals = ip_addrs.len();
+        stats.header_num_bytes = output.len();
+        stats.num_intervals = Some(self.interval_compressor.num_intervals());
+        stats.num_bits = Some(self.interval_compressor.num_bits);
+        stats.dict_size = Some(self.top_ips_sorted.len());
+        stats.dict_num_bits = Some(self.num_bits_for_most_common as u8);
 
-        let mut block_metadata = vec![];
+        for chunk in ip_addrs.chunks(self.block_size) {
+            if !self.local_dicts {
+                self.write_dict_block(
+                    chunk,
+                    &self.ip_to_ordinal,
+                    self.num_bits_for_most_common as u8,
+                    &mut output,
+                );
+                continue;
+            }
+            match self.choose_block_encoding(chunk) {
+                BlockEncoding::GlobalDict => {
+                    output.push(GLOBAL_DICT_BLOCK);
+                    self.write_dict_block(
+                        chunk,
+                        &self.ip_to_ordinal,
+                        self.num_bits_for_most_common as u8,
+                        &mut output,
+                    );
+                }
+                BlockEncoding::LocalDict(local_dict) => {
+                    output.push(LOCAL_DICT_BLOCK);
+                    self.write_local_dict(&local_dict, &mut output);
+                    self.write_dict_block(
+                        chunk,
+                        &local_dict.ip_to_ordinal,
+                        local_dict.num_bits,
+                        &mut output,
+                    );
+                }
+                BlockEncoding::Plain => {
+                    output.push(PLAIN_BLOCK);
+                    self.write_residuals(chunk, &mut output);
+                }
+            }
+        }
+        output.extend_from_slice(&[0u8; 7]);
+        stats.payload_num_bytes = output.len() - stats.header_num_bytes;
 
-        for chunk in iter {
-            //let mut chunk = chunk.to_vec();
-            let mut dict_enc = vec![];
-            let mut residual_data = vec![];
-            let mut bit_mask = BlockBitMask::default();
+        output
+    }
 
-            for (pos, el) in chunk.iter().enumerate() {
-                if let Some(ord) = self.ip_to_ordinal.get(el) {
-                    dict_enc.push(ord);
-                    bit_mask.set(pos);
-                } else {
-                    residual_data.push(el);
-                }
+    /// Picks the cheapest encoding for a block, according to `BlockMetaData`.
+    fn choose_block_encoding(&self, chunk: &[u128]) -> BlockEncoding {
+        let num_bits_other = self.interval_compressor.num_bits;
+        let plain_num_bits = chunk.len() * num_bits_other as usize;
+        let global_num_bits = BlockMetaData {
+            num_dict_encoded: chunk
+                .iter()
+                .filter(|ip_addr| self.ip_to_ordinal.contains_key(ip_addr))
+                .count(),
+            num_bits_for_dict_encoded: self.num_bits_for_most_common,
+            block_size: chunk.len(),
+            num_bits_other,
+            dict_num_bits: 0,
+        }
+        .get_num_bits();
+
+        let counts = chunk.iter().fold(FnvHashMap::default(), |mut counts, &ip| {
+            *counts.entry(ip).or_default() += 1;
+            counts
+        });
+        // Values occurring once are cheaper as residuals than as dictionary entries.
+        let top_ips: Vec<IPWithCount> = get_most_common(&counts, chunk.len())
+            .into_iter()
+            .take_while(|entry| entry.count > 1)
+            .collect();
+        let mut best_local: Option<(usize, usize)> = None;
+        let mut num_dict_encoded = 0;
+        // The two entry counts are accounted for as two bytes each.
+        let mut dict_num_bits = 32;
+        for (num_entries, entry) in top_ips.iter().enumerate().map(|(idx, e)| (idx + 1, e)) {
+            num_dict_encoded += entry.count;
+            dict_num_bits += if self.ip_to_ordinal.contains_key(&entry.ip) {
+                self.num_bits_for_most_common
+            } else {
+                num_bits_other as usize
+            };
+            let num_bits = BlockMetaData {
+                num_dict_encoded,
+                num_bits_for_dict_encoded: compute_num_bits(num_entries as u64 - 1) as usize,
+                block_size: chunk.len(),
+                num_bits_other,
+                dict_num_bits,
             }
+            .get_num_bits();
+            if best_local.is_none_or(|(best_num_bits, _)| num_bits < best_num_bits) {
+                best_local = Some((num_bits, num_entries));
+            }
+        }
 
-            block_metadata.push(BlockMetaData {
-                num_dict_encoded: dict_enc.len(),
-                num_bits_for_dict_encoded: self.num_bits_for_most_common,
-                block_size: self.block_size,
-                num_bits_other: self.interval_compressor.num_bits,
-            });
-            //let mut dict_entries = vec![];
+        let mut best = (plain_num_bits, BlockEncoding::Plain);
+        if global_num_bits < best.0 {
+            best = (global_num_bits, BlockEncoding::GlobalDict);
+        }
+        if let Some((local_num_bits, num_entries)) = best_local {
+            if local_num_bits < best.0 {
+                best = (
+                    local_num_bits,
+                    BlockEncoding::LocalDict(self.build_local_dict(&top_ips[..num_entries])),
+                );
+            }
         }
-        // TODO handle chunks remainder
+        best.1
+    }
 
-        let num_compressed_bytes: usize = block_metadata
+    fn build_local_dict(&self, top_ips: &[IPWithCount]) -> LocalDict {
+        let mut global_ordinals = Vec::new();
+        let mut ips_not_in_global_dict = Vec::new();
+        for entry in top_ips {
+            if let Some(&ord) = self.ip_to_ordinal.get(&entry.ip) {
+                global_ordinals.push(ord);
+            } else {
+                ips_not_in_global_dict.push(entry.ip);
+            }
+        }
+        global_ordinals.sort_unstable();
+        ips_not_in_global_dict.sort_unstable();
+        let ip_to_ordinal = global_ordinals
+            .iter()
+            .map(|&ord| self.top_ips_sorted[ord as usize])
+            .chain(ips_not_in_global_dict.iter().cloned())
+            .enumerate()
+            .map(|(ord, ip)| (ip, ord as u16))
+            .collect();
+        let compacts = ips_not_in_global_dict
             .iter()
-            .map(|block| block.get_num_bits())
-            .sum::<usize>()
-            / 8;
+            .map(|&ip| self.interval_compressor.to_compact(ip))
+            .collect();
+        LocalDict {
+            global_ordinals,
+            compacts,
+            ip_to_ordinal,
+            num_bits: compute_num_bits(top_ips.len() as u64 - 1),
+        }
+    }
 
-        println!(
-            "HalfDict + Compressed Space: compressed len: {}",
-            num_compressed_bytes
-        );
+    fn write_local_dict(&self, local_dict: &LocalDict, output: &mut Vec<u8>) {
+        serialize_vint(local_dict.global_ordinals.len() as u128, output);
+        serialize_vint(local_dict.compacts.len() as u128, output);
+        let mut bitpacker = BitPacker::default();
+        for &ord in &local_dict.global_ordinals {
+            bitpacker
+                .write(ord as u64, self.num_bits_for_most_common as u8, output)
+                .unwrap();
+        }
+        bitpacker.flush(output).unwrap();
+        for &compact in &local_dict.compacts {
+            bitpacker
+                .write(compact, self.interval_compressor.num_bits, output)
+                .unwrap();
+        }
+        bitpacker.flush(output).unwrap();
+    }
 
-        println!(
-            "Compression: {:.2}%",
-            100.0 * num_compressed_bytes as f64 / (ip_addrs.len() as f64 * 16.0)
-        );
+    /// Writes the bit mask of the values found in `ip_to_ordinal`, their ordinals,
+    /// and the other values in compact space.
+    fn write_dict_block(
+        &self,
+        chunk: &[u128],
+        ip_to_ordinal: &FnvHashMap<u128, u16>,
+        num_bits_for_ordinals: u8,
+        output: &mut Vec<u8>,
+    ) {
+        let mut dict_enc = vec![];
+        let mut residual_data = vec![];
+        let mut bit_mask = BlockBitMask::with_len(chunk.len());
+
+        for (pos, el) in chunk.iter().enumerate() {
+            if let Some(&ord) = ip_to_ordinal.get(el) {
+                dict_enc.push(ord);
+                bit_mask.set(pos);
+            } else {
+                residual_data.push(*el);
+            }
+        }
+
+        bit_mask.serialize_cheapest(chunk.len(), output);
+        let mut bitpacker = BitPacker::default();
+        for &ord in &dict_enc {
+            bitpacker
+                .write(ord as u64, num_bits_for_ordinals, output)
+                .unwrap();
+        }
+        bitpacker.flush(output).unwrap();
+        self.write_residuals(&residual_data, output);
+    }
+
+    fn write_residuals(&self, residual_data: &[u128], output: &mut Vec<u8>) {
+        let mut bitpacker = BitPacker::default();
+        for &ip_addr in residual_data {
+            let compact = self.interval_compressor.to_compact(ip_addr);
+            bitpacker
+                .write(compact, self.interval_compressor.num_bits, output)
+                .unwrap();
+        }
+        bitpacker.flush(output).unwrap();
+    }
+}
+
+/// Reads `num_vals` bitpacked values and returns the remaining data.
+fn read_bitpacked(
+    num_vals: usize,
+    num_bits: u8,
+    data: &[u8],
+    mut visitor: impl FnMut(u64) -> Result<(), IpReprError>,
+) -> Result<&[u8], IpReprError> {
+    check_bitpacked_len(num_vals, num_bits, data)?;
+    let bit_unpacker = BitUnpacker::new(num_bits);
+    for idx in 0..num_vals {
+        visitor(bit_unpacker.get(idx as u64, data))?;
+    }
+    let num_bytes = (num_vals * num_bits as usize).div_ceil(8);
+    Ok(&data[num_bytes..])
+}
+
+/// Reads a block written by `HalfDictCompressor::write_dict_block`, with `dict`
+/// the ip addresses of the ordinals.
+fn read_dict_block<'a>(
+    block_len: usize,
+    dict: &[u128],
+    num_bits_for_ordinals: u8,
+    decompressor: &IntervallDecompressor,
+    data: &'a [u8],
+    ip_addrs: &mut Vec<u128>,
+) -> Result<&'a [u8], IpReprError> {
+    let (bit_mask, data) = BlockBitMask::deserialize_any(block_len, data)?;
+    let num_dict_encoded = bit_mask.count_ones();
 
-        vec![]
+    let mut ordinals = Vec::with_capacity(num_dict_encoded);
+    let data = read_bitpacked(num_dict_encoded, num_bits_for_ordinals, data, |ord| {
+        let ip_addr = dict.get(ord as usize).ok_or(IpReprError::InvalidOrdinal)?;
+        ordinals.push(*ip_addr);
+        Ok(())
+    })?;
+    let mut residuals = Vec::with_capacity(block_len - num_dict_encoded);
+    let data = read_bitpacked(
+        block_len - num_dict_encoded,
+        decompressor.num_bits,
+        data,
+        |compact| {
+            residuals.push(decompressor.checked_compact_to_ip_addr(compact)?);
+            Ok(())
+        },
+    )?;
+
+    let mut ordinals_it = ordinals.iter();
+    let mut residuals_it = residuals.iter();
+    for pos in 0..block_len {
+        let ip_addr = if bit_mask.is_set(pos) {
+            ordinals_it.next()
+        } else {
+            residuals_it.next()
+        };
+        ip_addrs.push(*ip_addr.unwrap());
     }
+    Ok(data)
+}
+
+/// Reads a dictionary written by `HalfDictCompressor::write_local_dict`, and
+/// returns its ip addresses.
+fn read_local_dict<'a>(
+    block_len: usize,
+    top_ips_sorted: &[u128],
+    num_bits_for_most_common: u8,
+    decompressor: &IntervallDecompressor,
+    data: &'a [u8],
+) -> Result<(Vec<u128>, &'a [u8]), IpReprError> {
+    let (num_global_ordinals, data) = deserialize_vint(data)?;
+    let (num_compacts, data) = deserialize_vint(data)?;
+    let num_entries = num_global_ordinals + num_compacts;
+    if num_entries == 0 || num_entries > block_len as u128 {
+        return Err(IpReprError::CorruptedData);
+    }
+    let mut local_dict = Vec::with_capacity(num_entries as usize);
+    let data = read_bitpacked(
+        num_global_ordinals as usize,
+        num_bits_for_most_common,
+        data,
+        |ord| {
+            let ip_addr = top_ips_sorted
+                .get(ord as usize)
+                .ok_or(IpReprError::InvalidOrdinal)?;
+            local_dict.push(*ip_addr);
+            Ok(())
+        },
+    )?;
+    let data = read_bitpacked(
+        num_compacts as usize,
+        decompressor.num_bits,
+        data,
+        |compact| {
+            local_dict.push(decompressor.checked_compact_to_ip_addr(compact)?);
+            Ok(())
+        },
+    )?;
+    Ok((local_dict, data))
+}
+
+fn decompress(data: &[u8]) -> Result<Vec<u128>, IpReprError> {
+    let (decompressor, data) = IntervallDecompressor::open(data)?;
+    let (block_size, data) = deserialize_vint(data)?;
+    if block_size == 0 || block_size > MAX_BLOCK_SIZE as u128 {
+        return Err(IpReprError::CorruptedData);
+    }
+    let block_size = block_size as usize;
+    let (&num_bits_byte, data) = data.split_first().ok_or(IpReprError::TruncatedHeader)?;
+    let local_dicts = num_bits_byte & LOCAL_DICTS_FLAG != 0;
+    let elias_fano_dict = num_bits_byte & ELIAS_FANO_DICT_FLAG != 0;
+    let num_bits_for_most_common = num_bits_byte & !(LOCAL_DICTS_FLAG | ELIAS_FANO_DICT_FLAG);
+    if num_bits_for_most_common as usize > MAX_NUM_BITS_FOR_MOST_COMMON {
+        return Err(IpReprError::InvalidNumBits(num_bits_for_most_common));
+    }
+    let (num_vals, data) = deserialize_num_vals(data)?;
+    let (top_ips_sorted, mut data) = if elias_fano_dict {
+        let (elias_fano, data) = EliasFano::deserialize(data)?;
+        let top_ips_sorted = elias_fano
+            .iter()
+            .map(|compact| decompressor.checked_compact_to_ip_addr(compact))
+            .collect::<Result<_, _>>()?;
+        (top_ips_sorted, data)
+    } else {
+        deserialize_sorted_ips(data)?
+    };
+    if top_ips_sorted.len() > 1 << num_bits_for_most_common {
+        return Err(IpReprError::InvalidOrdinal);
+    }
+
+    let mut ip_addrs = Vec::with_capacity(num_vals);
+    while ip_addrs.len() < num_vals {
+        let block_len = block_size.min(num_vals - ip_addrs.len());
+        let block_encoding = if local_dicts {
+            let (&block_encoding, new_data) =
+                data.split_first().ok_or(IpReprError::TruncatedData)?;
+            data = new_data;
+            block_encoding
+        } else {
+            GLOBAL_DICT_BLOCK
+        };
+        data = match block_encoding {
+            GLOBAL_DICT_BLOCK => read_dict_block(
+                block_len,
+                &top_ips_sorted,
+                num_bits_for_most_common,
+                &decompressor,
+                data,
+                &mut ip_addrs,
+            )?,
+            LOCAL_DICT_BLOCK => {
+                let (local_dict, data) = read_local_dict(
+                    block_len,
+                    &top_ips_sorted,
+                    num_bits_for_most_common,
+                    &decompressor,
+                    data,
+                )?;
+                let num_bits = compute_num_bits(local_dict.len() as u64 - 1);
+                read_dict_block(
+                    block_len,
+                    &local_dict,
+                    num_bits,
+                    &decompressor,
+                    data,
+                    &mut ip_addrs,
+                )?
+            }
+            PLAIN_BLOCK => read_bitpacked(block_len, decompressor.num_bits, data, |compact| {
+                ip_addrs.push(decompressor.checked_compact_to_ip_addr(compact)?);
+                Ok(())
+            })?,
+            _ => return Err(IpReprError::CorruptedData),
+        };
+    }
+    Ok(ip_addrs)
 }
 
 impl HalfDict {
-    fn train(&self, ip_addrs: &[u128]) -> HalfDictCompressor {
-        let mut ip_addrs_sorted = ip_addrs.to_vec();
-        ip_addrs_sorted.sort();
-        let interval_compressor = train(&ip_addrs_sorted, 64);
+    fn train(&self, counts: &ValueCounts) -> HalfDictCompressor {
+        let interval_compressor =
+            train_on_distinct(&counts.sorted_distinct(), counts.num_vals(), 64);
+        let top_ips_ordered = counts.most_common(1 << self.num_bits_for_most_common);
+        self.build_compressor(interval_compressor, top_ips_ordered)
+    }
 
-        let top_ips_ordered = get_most_common(ip_addrs, 1 << self.num_bits_for_most_common);
+    /// Same as `train`, with the dictionary selected by `HeavyHitters` and the
+    /// intervals trained on a sample, see `train_on_sample`.
+    ///
+    /// Falls back to training the intervals on the `max_counters` largest gaps of the
+    /// column, see `train_streaming`, if the sample misses too much of it.
+    fn train_bounded(&self, ip_addrs: &[u128], max_counters: usize) -> HalfDictCompressor {
+        let interval_compressor = train_on_sample(ip_addrs, 64)
+            .unwrap_or_else(|| train_streaming(ip_addrs, max_counters, 64));
+        let dict_size = 1 << self.num_bits_for_most_common;
+        let heavy_hitters = HeavyHitters::from_ip_addrs(ip_addrs, max_counters.max(dict_size));
+        let top_ips_ordered = heavy_hitters.most_common(dict_size);
+        self.build_compressor(interval_compressor, top_ips_ordered)
+    }
 
-        let ip_to_ordinal: FnvHashMap<u128, u16> = top_ips_ordered
+    fn build_compressor(
+        &self,
+        interval_compressor: IntervalCompressor,
+        mut top_ips_ordered: Vec<IPWithCount>,
+    ) -> HalfDictCompressor {
+        let mut num_bits_for_most_common = self.num_bits_for_most_common;
+        if self.adaptive_dict_size {
+            let dict_size = choose_dict_size(
+                &top_ips_ordered,
+                interval_compressor.num_bits,
+                self.num_bits_for_most_common,
+            );
+            num_bits_for_most_common = dict_size.num_bits;
+            top_ips_ordered.truncate(dict_size.num_entries);
+        }
+        let top_ips_sorted: Vec<u128> = top_ips_ordered
+            .iter()
+            .map(|entry| entry.ip)
+            .sorted()
+            .collect();
+
+        let ip_to_ordinal: FnvHashMap<u128, u16> = top_ips_sorted
             .iter()
             .enumerate()
-            .map(|(ord, entry)| (entry.ip, ord as u16))
+            .map(|(ord, &ip)| (ip, ord as u16))
             .collect();
 
         HalfDictCompressor {
             interval_compressor,
-            //top_ips_ordered,
+            top_ips_sorted,
             ip_to_ordinal,
             block_size: self.block_size,
-            num_bits_for_most_common: self.num_bits_for_most_common,
+            num_bits_for_most_common,
+            local_dicts: self.local_dicts,
         }
     }
 }
 
 impl IpRepr for HalfDict {
-    fn encode(&self, ip_addrs: &[u128]) -> Vec<u8> {
+    fn codec_id(&self) -> CodecId {
+        CodecId::HalfDict
+    }
+
+    fn params(&self) -> Vec<u64> {
+        vec![
+            self.block_size as u64,
+            self.num_bits_for_most_common as u64,
+            self.local_dicts as u64,
+            self.adaptive_dict_size as u64,
+        ]
+    }
+
+    fn encode_with_stats(
+        &self,
+        ip_addrs: &[u128],
+    ) -> Result<(Vec<u8>, CompressionStats), IpReprError> {
+        if let Some(max_counters) = self.max_counters {
+            let mut stats = CompressionStats::default();
+            if ip_addrs.is_empty() {
+                return Ok((Vec::new(), stats));
+            }
+            let start = Instant::now();
+            let compressor = self.train_bounded(ip_addrs, max_counters);
+            stats.train_time = start.elapsed();
+            let start = Instant::now();
+            let compressed = compressor.compress(ip_addrs, &mut stats);
+            stats.compress_time = start.elapsed();
+            return Ok((compressed, stats));
+        }
+        let start = Instant::now();
+        let counts = ValueCounts::from_ip_addrs(ip_addrs);
+        let count_time = start.elapsed();
+        let (compressed, mut stats) = self.encode_with_counts(ip_addrs, &counts)?;
+        stats.train_time += count_time;
+        Ok((compressed, stats))
+    }
+
+    /// `max_counters` is ignored: it bounds the memory used to count the values,
+    /// and `counts` are exact and already computed.
+    fn encode_with_counts(
+        &self,
+        ip_addrs: &[u128],
+        counts: &ValueCounts,
+    ) -> Result<(Vec<u8>, CompressionStats), IpReprError> {
+        let mut stats = CompressionStats::default();
         if ip_addrs.is_empty() {
-            return Vec::new();
+            return Ok((Vec::new(), stats));
         }
 
         let start = Instant::now();
-        let compressor = self.train(ip_addrs);
-        println!("Train Time: {}", (Instant::now() - start).as_millis());
+        let compressor = self.train(counts);
+        stats.train_time = start.elapsed();
         let start = Instant::now();
-        let compressed = compressor.compress(ip_addrs);
-        println!("Estimate Time: {}", (Instant::now() - start).as_millis());
-        compressed
+        let compressed = compressor.compress(ip_addrs, &mut stats);
+        stats.compress_time = start.elapsed();
+        Ok((compressed, stats))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u128>, IpReprError> {
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+        decompress(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_aux_vals(half_dict: &HalfDict, ip_addrs: &[u128]) -> Vec<u8> {
+        let data = half_dict.encode(ip_addrs).unwrap();
+        let decoded_ip_addrs = half_dict.decode(&data).unwrap();
+        assert_eq!(&decoded_ip_addrs, ip_addrs);
+        data
+    }
+
+    fn skewed_ip_addrs(num_vals: usize) -> Vec<u128> {
+        (0..num_vals as u128)
+            .map(|i| {
+                if i % 3 == 0 {
+                    (i << 40) | i
+                } else {
+                    0xffff_0a00_0000 + (i * 7919) % 4096
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_half_dict_round_trip() {
+        test_aux_vals(&HalfDict::new(1024, 8), &skewed_ip_addrs(10_000));
+        test_aux_vals(&HalfDict::new(128, 4), &skewed_ip_addrs(1_000));
+        test_aux_vals(&HalfDict::new(64, 0), &skewed_ip_addrs(100));
+        test_aux_vals(&HalfDict::new(1024, 8), &[1_000_000_000u128; 3]);
+        test_aux_vals(&HalfDict::new(1024, 8), &[]);
+    }
+
+    #[test]
+    fn test_half_dict_max_counters() {
+        let ip_addrs = skewed_ip_addrs(10_000);
+        test_aux_vals(&HalfDict::new(1024, 4).with_max_counters(64), &ip_addrs);
+        test_aux_vals(&HalfDict::new(128, 4).with_max_counters(1), &ip_addrs);
+        test_aux_vals(&HalfDict::new(128, 4).with_max_counters(1), &[]);
+        // Larger than the sample the intervals are trained on.
+        let ip_addrs = skewed_ip_addrs(200_000);
+        test_aux_vals(&HalfDict::new(1024, 4).with_max_counters(64), &ip_addrs);
+    }
+
+    /// Each block has its own hot ip addresses.
+    fn ip_addrs_with_locality(num_vals: usize) -> Vec<u128> {
+        (0..num_vals as u128)
+            .map(|i| {
+                let block = i / 1024;
+                match i % 5 {
+                    0 => 0xffff_0a00_0000 + (i * 7919) % 100_000,
+                    1 => 0xffff_c0a8_0001,
+                    _ => 0xffff_0b00_0000 + block * 1000 + (i * 31) % 16,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_half_dict_local_dicts() {
+        let ip_addrs = ip_addrs_with_locality(20 * 1024 + 100);
+        let data_global = test_aux_vals(&HalfDict::new(1024, 4), &ip_addrs);
+        let data_local = test_aux_vals(&HalfDict::new(1024, 4).with_local_dicts(true), &ip_addrs);
+        assert!(data_local.len() < data_global.len());
+        test_aux_vals(&HalfDict::new(128, 0).with_local_dicts(true), &ip_addrs);
+        // Without repeated values, blocks are not dictionary encoded.
+        let distinct: Vec<u128> = (0..3000u128).map(|i| i * i).collect();
+        test_aux_vals(&HalfDict::new(1024, 8).with_local_dicts(true), &distinct);
+        test_aux_vals(
+            &HalfDict::new(1024, 8).with_local_dicts(true),
+            &skewed_ip_addrs(5000),
+        );
+        test_aux_vals(&HalfDict::new(1024, 8).with_local_dicts(true), &[7; 3]);
+    }
+
+    #[test]
+    fn test_half_dict_local_dicts_truncated() {
+        let half_dict = HalfDict::new(64, 2).with_local_dicts(true);
+        let data = test_aux_vals(&half_dict, &ip_addrs_with_locality(300));
+        for len in 1..data.len() - 7 {
+            assert!(half_dict.decode(&data[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_choose_dict_size() {
+        let top_ips = |counts: &[usize]| -> Vec<IPWithCount> {
+            counts
+                .iter()
+                .enumerate()
+                .map(|(ip, &count)| IPWithCount {
+                    ip: ip as u128,
+                    count,
+                })
+                .collect()
+        };
+        let no_dict = DictSize {
+            num_bits: 0,
+            num_entries: 0,
+        };
+        assert_eq!(choose_dict_size(&[], 20, 16), no_dict);
+        // Values occurring once do not pay for their entry.
+        assert_eq!(choose_dict_size(&top_ips(&[1; 100]), 20, 16), no_dict);
+        assert_eq!(
+            choose_dict_size(&top_ips(&[1000, 2, 1]), 20, 16),
+            DictSize {
+                num_bits: 0,
+                num_entries: 1
+            }
+        );
+        assert_eq!(
+            choose_dict_size(&top_ips(&[1000, 1000, 1000, 1]), 20, 16),
+            DictSize {
+                num_bits: 2,
+                num_entries: 3
+            }
+        );
+        // Bounded by the maximum number of bits.
+        assert_eq!(
+            choose_dict_size(&top_ips(&[1000; 100]), 20, 4),
+            DictSize {
+                num_bits: 4,
+                num_entries: 16
+            }
+        );
+    }
+
+    #[test]
+    fn test_half_dict_adaptive_dict_size() {
+        let ip_addrs = skewed_ip_addrs(50_000);
+        let half_dict = HalfDict::new(1024, 16).with_adaptive_dict_size(true);
+        let (data, stats) = half_dict.encode_with_stats(&ip_addrs).unwrap();
+        assert_eq!(half_dict.decode(&data).unwrap(), ip_addrs);
+        let dict_num_bits = stats.dict_num_bits.unwrap();
+        assert!(dict_num_bits < 16);
+        assert!(stats.dict_size.unwrap() <= 1 << dict_num_bits);
+        for num_bits in [0, 4, 8, 12, 16] {
+            let fixed = HalfDict::new(1024, num_bits).encode(&ip_addrs).unwrap();
+            assert!(data.len() <= fixed.len(), "{num_bits} bits");
+        }
+        test_aux_vals(
+            &HalfDict::new(64, 8)
+                .with_adaptive_dict_size(true)
+                .with_local_dicts(true)
+                .with_max_counters(16),
+            &ip_addrs,
+        );
+        let distinct: Vec<u128> = (0..3000u128).map(|i| i * i).collect();
+        let (_, stats) = half_dict.encode_with_stats(&distinct).unwrap();
+        assert_eq!(stats.dict_size, Some(0));
+    }
+
+    #[test]
+    fn test_half_dict_partial_block() {
+        let ip_addrs = skewed_ip_addrs(1024 + 5);
+        let data = test_aux_vals(&HalfDict::new(1024, 8), &ip_addrs);
+        let data_full_blocks = test_aux_vals(&HalfDict::new(1024, 8), &ip_addrs[..1024]);
+        assert!(data.len() > data_full_blocks.len());
+    }
+
+    #[test]
+    fn test_half_dict_large_blocks() {
+        let ip_addrs = skewed_ip_addrs(20_000);
+        test_aux_vals(&HalfDict::new(4096, 8), &ip_addrs);
+        test_aux_vals(&HalfDict::new(1 << 16, 8), &ip_addrs);
+        test_aux_vals(&HalfDict::new(5000, 8).with_local_dicts(true), &ip_addrs);
+        assert_eq!(
+            HalfDict::try_new((1 << 16) + 1, 8).unwrap_err(),
+            IpReprError::InvalidParams
+        );
+    }
+
+    #[test]
+    fn test_half_dict_sparse_dict_blocks() {
+        // Few dictionary encoded values per block, found at sparse positions or in
+        // runs.
+        let sparse: Vec<u128> = (0..10_000u128)
+            .map(|i| if i % 100 == 0 { 7 } else { i * i })
+            .collect();
+        let runs: Vec<u128> = (0..10_000u128)
+            .map(|i| if i % 1000 < 300 { 7 } else { i * i })
+            .collect();
+        for ip_addrs in [sparse, runs] {
+            let half_dict = HalfDict::new(1024, 0);
+            let (data, stats) = half_dict.encode_with_stats(&ip_addrs).unwrap();
+            assert_eq!(half_dict.decode(&data).unwrap(), ip_addrs);
+            let num_residuals = ip_addrs.iter().filter(|&&ip_addr| ip_addr != 7).count();
+            let residuals_len = (num_residuals * stats.num_bits.unwrap() as usize).div_ceil(8);
+            // A bit mask takes 128 bytes per block.
+            let num_blocks = ip_addrs.len().div_ceil(1024);
+            assert!(stats.payload_num_bytes < residuals_len + num_blocks * 32);
+        }
+    }
+
+    #[test]
+    fn test_bit_mask_presence_kinds() {
+        let block_len = 1000;
+        let mut bit_masks = Vec::new();
+        for positions in [
+            vec![],
+            vec![0, 999],
+            (0..1000).step_by(2).collect(),
+            (100..400).chain(700..1000).collect(),
+            (0..1000).collect::<Vec<usize>>(),
+        ] {
+            let mut bit_mask = BlockBitMask::with_len(block_len);
+            for &pos in &positions {
+                bit_mask.set(pos);
+            }
+            let mut data = Vec::new();
+            bit_mask.serialize_cheapest(block_len, &mut data);
+            bit_masks.push(data[0]);
+            data.extend_from_slice(&[0u8; 7]);
+            let (deserialized, remaining) =
+                BlockBitMask::deserialize_any(block_len, &data).unwrap();
+            assert_eq!(remaining.len(), 7);
+            let deserialized_positions: Vec<usize> = (0..block_len)
+                .filter(|&pos| deserialized.is_set(pos))
+                .collect();
+            assert_eq!(deserialized_positions, positions);
+            for len in 0..data.len() - 7 {
+                assert!(BlockBitMask::deserialize_any(block_len, &data[..len]).is_err());
+            }
+        }
+        assert_eq!(
+            bit_masks,
+            [
+                PRESENCE_POSITIONS,
+                PRESENCE_POSITIONS,
+                PRESENCE_BIT_MASK,
+                PRESENCE_RUNS,
+                PRESENCE_RUNS
+            ]
+        );
+    }
+
+    #[test]
+    fn test_half_dict_elias_fano_dict() {
+        let ip_addrs: Vec<u128> = (0..50_000u128)
+            .map(|i| 0xffff_0a00_0000 + (i * 7919) % 4096)
+            .collect();
+        let half_dict = HalfDict::new(1024, 12);
+        let (data, stats) = half_dict.encode_with_stats(&ip_addrs).unwrap();
+        assert_eq!(half_dict.decode(&data).unwrap(), ip_addrs);
+        assert_eq!(stats.dict_size, Some(4096));
+        // Delta encoded, the dictionary would take a byte per entry.
+        assert!(stats.header_num_bytes < 2048);
+    }
+
+    #[test]
+    fn test_half_dict_truncated() {
+        let half_dict = HalfDict::new(64, 4);
+        let data = test_aux_vals(&half_dict, &skewed_ip_addrs(300));
+        for len in 1..data.len() - 7 {
+            assert!(half_dict.decode(&data[..len]).is_err());
+        }
     }
 
-    fn decode(&self, _data: &[u8]) -> Vec<u128> {
-        unimplemented!()
+    #[test]
+    fn test_half_dict_invalid_block_size() {
+        let half_dict = HalfDict::new(64, 4);
+        let mut data = test_aux_vals(&half_dict, &skewed_ip_addrs(300));
+        let (_, rest) = IntervallDecompressor::open(&data).unwrap();
+        // The block size is a single byte vint following the interval header.
+        let block_size_pos = data.len() - rest.len();
+        data[block_size_pos] = 0;
+        assert_eq!(half_dict.decode(&data), Err(IpReprError::CorruptedData));
     }
 }
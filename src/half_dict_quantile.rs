@@ -1,92 +1,398 @@
 use std::{fmt::Debug, time::Instant};
 
 use fnv::FnvHashMap;
+use itertools::Itertools;
+use q_compress::errors::{ErrorKind, QCompressError};
 
-use crate::{get_most_common, IPWithCount, IpRepr};
+use crate::{
+    elias_fano::{deserialize_sorted_ips_elias_fano, serialize_sorted_ips_elias_fano},
+    heavy_hitters::HeavyHitters,
+    interval::{deserialize_sorted_ips, serialize_sorted_ips},
+    CodecId, CompressionStats, IPWithCount, IpRepr, IpReprError, ValueCounts,
+};
 
 #[derive(Debug)]
 pub struct HalfDictQ {
     num_most_common: usize,
+    max_counters: Option<usize>,
+    adaptive_dict_size: bool,
+    elias_fano_dict: bool,
 }
 
 impl HalfDictQ {
     pub fn new(num_most_common: usize) -> HalfDictQ {
-        HalfDictQ { num_most_common }
+        HalfDictQ {
+            num_most_common,
+            max_counters: None,
+            adaptive_dict_size: false,
+            elias_fano_dict: false,
+        }
+    }
+
+    /// Selects the dictionary with the Space-Saving algorithm, tracking at most
+    /// `max_counters` values instead of counting all of the distinct values.
+    ///
+    /// At least as many counters as dictionary entries are used.
+    pub fn with_max_counters(mut self, max_counters: usize) -> HalfDictQ {
+        self.max_counters = Some(max_counters);
+        self
+    }
+
+    /// Picks the size of the dictionary from the frequencies of the values, see
+    /// `choose_num_most_common`. `num_most_common` becomes an upper bound.
+    pub fn with_adaptive_dict_size(mut self, adaptive_dict_size: bool) -> HalfDictQ {
+        self.adaptive_dict_size = adaptive_dict_size;
+        self
     }
+
+    /// Stores the dictionary with `SortedEncoding` instead of delta encoding it,
+    /// which is smaller for large dictionaries.
+    ///
+    /// The setting is written in the payload, so any `HalfDictQ` decodes the column.
+    pub fn with_elias_fano_dict(mut self, elias_fano_dict: bool) -> HalfDictQ {
+        self.elias_fano_dict = elias_fano_dict;
+        self
+    }
+}
+
+/// Returns the number of entries of the dictionary, with `top_ips_ordered` the
+/// most common values, most common first.
+///
+/// `q_compress` entropy codes the remapped values: an ordinal whose value occurs
+/// `count` times costs about `log2(num_vals / count)` bits, while the other values
+/// cost about `log2(num_distinct)` bits. The frequency curve is walked until the
+/// marginal entry no longer saves more bits than it costs in the header.
+fn choose_num_most_common(
+    top_ips_ordered: &[IPWithCount],
+    num_vals: usize,
+    num_distinct: usize,
+) -> usize {
+    // About the size of the delta of two IPv4 addresses, as a vint.
+    const ENTRY_NUM_BITS: f64 = 32.0;
+    let residual_num_bits = (num_distinct as f64).log2();
+    top_ips_ordered
+        .iter()
+        .take_while(|entry| {
+            let ordinal_num_bits = (num_vals as f64 / entry.count as f64).log2();
+            entry.count as f64 * (residual_num_bits - ordinal_num_bits) > ENTRY_NUM_BITS
+        })
+        .count()
 }
 
+/// Set in the flags byte when the dictionary is stored with `SortedEncoding`.
+const ELIAS_FANO_DICT_FLAG: u8 = 0x01;
+
+/// The most common ip addresses are remapped to their ordinal in the sorted dictionary,
+/// the others are shifted by the size of the dictionary.
 struct HalfDictCompressorQ {
-    top_ips_ordered: Vec<IPWithCount>,
+    top_ips_sorted: Vec<u128>,
     remapped_ip_addr: Vec<u128>,
+    elias_fano_dict: bool,
 }
 
+/// Layout:
+/// - a flags byte, `ELIAS_FANO_DICT_FLAG` or 0
+/// - the dictionary, sorted and delta encoded, or with `SortedEncoding`
+/// - the remapped ip addresses, compressed with `q_compress`
 impl HalfDictCompressorQ {
-    fn compress(&self, _ip_addrs: &[u128]) -> Vec<u8> {
+    fn compress(&self, stats: &mut CompressionStats) -> Result<Vec<u8>, IpReprError> {
+        let mut output = Vec::new();
+        output.push(if self.elias_fano_dict {
+            ELIAS_FANO_DICT_FLAG
+        } else {
+            0
+        });
+        if self.elias_fano_dict {
+            serialize_sorted_ips_elias_fano(&self.top_ips_sorted, &mut output)?;
+        } else {
+            serialize_sorted_ips(&self.top_ips_sorted, &mut output);
+        }
+        stats.num_vals = self.remapped_ip_addr.len();
+        stats.header_num_bytes = output.len();
+        stats.dict_size = Some(self.top_ips_sorted.len());
         let bytes: Vec<u8> = q_compress::auto_compress(&self.remapped_ip_addr, 10);
+        output.extend_from_slice(&bytes);
+        stats.payload_num_bytes = bytes.len();
+        Ok(output)
+    }
+}
 
-        let storing_dict = self.top_ips_ordered.len() * 16;
-        let num_compressed_bytes = bytes.len() + storing_dict;
-        println!(
-            "HalfDictQ: TopNRemapped:{} - compressed len: {}",
-            self.top_ips_ordered.len(),
-            num_compressed_bytes
-        );
-        println!(
-            "Compression: {:.2}%",
-            100.0 * num_compressed_bytes as f64 / (_ip_addrs.len() as f64 * 16.0)
-        );
+fn to_ip_repr_error(error: QCompressError) -> IpReprError {
+    match error.kind {
+        ErrorKind::InsufficientData => IpReprError::TruncatedData,
+        _ => IpReprError::CorruptedData,
+    }
+}
 
-        bytes
+fn decompress(data: &[u8]) -> Result<Vec<u128>, IpReprError> {
+    let (&flags, data) = data.split_first().ok_or(IpReprError::TruncatedHeader)?;
+    if flags & !ELIAS_FANO_DICT_FLAG != 0 {
+        return Err(IpReprError::CorruptedData);
     }
+    let (top_ips_sorted, data) = if flags & ELIAS_FANO_DICT_FLAG != 0 {
+        deserialize_sorted_ips_elias_fano(data)?
+    } else {
+        deserialize_sorted_ips(data)?
+    };
+    let num_most_common = top_ips_sorted.len() as u128;
+    let remapped_ip_addrs: Vec<u128> =
+        q_compress::auto_decompress(data).map_err(to_ip_repr_error)?;
+    let ip_addrs = remapped_ip_addrs
+        .into_iter()
+        .map(|remapped| {
+            if remapped < num_most_common {
+                top_ips_sorted[remapped as usize]
+            } else {
+                remapped - num_most_common
+            }
+        })
+        .collect();
+    Ok(ip_addrs)
 }
 
 impl HalfDictQ {
-    fn train(&self, ip_addrs: &[u128]) -> HalfDictCompressorQ {
-        let num_most_common = self.num_most_common;
-        let top_ips_ordered = get_most_common(ip_addrs, num_most_common);
-        let ip_to_ordinal: FnvHashMap<u128, u128> = top_ips_ordered
+    fn train(
+        &self,
+        ip_addrs: &[u128],
+        top_ips_ordered: &[IPWithCount],
+    ) -> Result<HalfDictCompressorQ, IpReprError> {
+        let top_ips_sorted: Vec<u128> = top_ips_ordered
+            .iter()
+            .map(|entry| entry.ip)
+            .sorted()
+            .collect();
+        let num_most_common = top_ips_sorted.len() as u128;
+        let ip_to_ordinal: FnvHashMap<u128, u128> = top_ips_sorted
             .iter()
             .enumerate()
-            .map(|(ord, entry)| (entry.ip, ord as u128))
+            .map(|(ord, &ip)| (ip, ord as u128))
             .collect();
 
         let remapped_ip_addr = ip_addrs
             .iter()
-            .map(|ip| {
-                ip_to_ordinal
-                    .get(ip)
-                    .cloned()
-                    .unwrap_or(ip + num_most_common as u128)
+            .map(|&ip| {
+                if let Some(&ord) = ip_to_ordinal.get(&ip) {
+                    return Ok(ord);
+                }
+                ip.checked_add(num_most_common)
+                    .ok_or(IpReprError::ValueOutOfRange(ip))
             })
-            .collect();
+            .collect::<Result<_, _>>()?;
 
-        HalfDictCompressorQ {
-            top_ips_ordered,
+        Ok(HalfDictCompressorQ {
+            top_ips_sorted,
             remapped_ip_addr,
+            elias_fano_dict: self.elias_fano_dict,
+        })
+    }
+
+    fn truncate_dict(
+        &self,
+        mut top_ips_ordered: Vec<IPWithCount>,
+        num_vals: usize,
+        num_distinct: usize,
+    ) -> Vec<IPWithCount> {
+        if self.adaptive_dict_size {
+            let num_most_common = choose_num_most_common(&top_ips_ordered, num_vals, num_distinct);
+            top_ips_ordered.truncate(num_most_common);
         }
+        top_ips_ordered
     }
-}
 
-impl IpRepr for HalfDictQ {
-    fn encode(&self, ip_addrs: &[u128]) -> Vec<u8> {
+    fn encode_with_top_ips(
+        &self,
+        ip_addrs: &[u128],
+        top_ips_ordered: &[IPWithCount],
+    ) -> Result<(Vec<u8>, CompressionStats), IpReprError> {
+        let mut stats = CompressionStats::default();
         if ip_addrs.is_empty() {
-            return Vec::new();
+            return Ok((Vec::new(), stats));
         }
         let start = Instant::now();
-        let compressor = self.train(ip_addrs);
-        println!("Train Time: {}ms", (Instant::now() - start).as_millis());
+        let compressor = self.train(ip_addrs, top_ips_ordered)?;
+        stats.train_time = start.elapsed();
         let start = Instant::now();
-        let compressed = compressor.compress(ip_addrs);
-        println!("Compress Time: {}ms", (Instant::now() - start).as_millis());
-        //println!(
-        //"Elems / s: {}",
-        //ip_addrs.len() as f32 / ((Instant::now() - start).as_millis() as f32 / 1000.0)
-        //);
+        let compressed = compressor.compress(&mut stats)?;
+        stats.compress_time = start.elapsed();
+        Ok((compressed, stats))
+    }
+}
+
+impl IpRepr for HalfDictQ {
+    fn codec_id(&self) -> CodecId {
+        CodecId::HalfDictQ
+    }
 
-        compressed
+    fn params(&self) -> Vec<u64> {
+        vec![
+            self.num_most_common as u64,
+            self.adaptive_dict_size as u64,
+            self.elias_fano_dict as u64,
+        ]
     }
 
-    fn decode(&self, _data: &[u8]) -> Vec<u128> {
-        unimplemented!()
+    fn encode_with_stats(
+        &self,
+        ip_addrs: &[u128],
+    ) -> Result<(Vec<u8>, CompressionStats), IpReprError> {
+        let start = Instant::now();
+        let top_ips_ordered = if let Some(max_counters) = self.max_counters {
+            let max_counters = max_counters.max(self.num_most_common).max(1);
+            let heavy_hitters = HeavyHitters::from_ip_addrs(ip_addrs, max_counters);
+            let top_ips_ordered = heavy_hitters.most_common(self.num_most_common);
+            self.truncate_dict(
+                top_ips_ordered,
+                ip_addrs.len(),
+                heavy_hitters.num_distinct(),
+            )
+        } else {
+            let counts = ValueCounts::from_ip_addrs(ip_addrs);
+            let top_ips_ordered = counts.most_common(self.num_most_common);
+            self.truncate_dict(top_ips_ordered, counts.num_vals(), counts.num_distinct())
+        };
+        let count_time = start.elapsed();
+        let (compressed, mut stats) = self.encode_with_top_ips(ip_addrs, &top_ips_ordered)?;
+        stats.train_time += count_time;
+        Ok((compressed, stats))
+    }
+
+    fn encode_with_counts(
+        &self,
+        ip_addrs: &[u128],
+        counts: &ValueCounts,
+    ) -> Result<(Vec<u8>, CompressionStats), IpReprError> {
+        let top_ips_ordered = self.truncate_dict(
+            counts.most_common(self.num_most_common),
+            counts.num_vals(),
+            counts.num_distinct(),
+        );
+        self.encode_with_top_ips(ip_addrs, &top_ips_ordered)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u128>, IpReprError> {
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+        decompress(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_aux_vals(half_dict_q: &HalfDictQ, ip_addrs: &[u128]) {
+        let data = half_dict_q.encode(ip_addrs).unwrap();
+        let decoded_ip_addrs = half_dict_q.decode(&data).unwrap();
+        assert_eq!(&decoded_ip_addrs, ip_addrs);
+    }
+
+    #[test]
+    fn test_compress() {
+        let ip_addrs = &[
+            1u128,
+            100u128,
+            3u128,
+            99999u128,
+            100000u128,
+            100001u128,
+            4_000_211_221u128,
+            4_000_211_222u128,
+            333u128,
+            333u128,
+            333u128,
+        ];
+        test_aux_vals(&HalfDictQ::new(2), ip_addrs);
+        test_aux_vals(&HalfDictQ::new(0), ip_addrs);
+        test_aux_vals(&HalfDictQ::new(2).with_max_counters(2), ip_addrs);
+        test_aux_vals(&HalfDictQ::new(0).with_max_counters(0), ip_addrs);
+        // Dictionary encoded values are never shifted.
+        test_aux_vals(&HalfDictQ::new(1024), &[1u128, u128::MAX, 3u128]);
+    }
+
+    #[test]
+    fn test_choose_num_most_common() {
+        let top_ips: Vec<IPWithCount> = [500, 200, 100, 2, 1]
+            .iter()
+            .enumerate()
+            .map(|(ip, &count)| IPWithCount {
+                ip: ip as u128,
+                count,
+            })
+            .collect();
+        assert_eq!(choose_num_most_common(&top_ips, 1000, 300), 3);
+        assert_eq!(choose_num_most_common(&top_ips[3..], 1000, 300), 0);
+        assert_eq!(choose_num_most_common(&[], 1000, 300), 0);
+    }
+
+    #[test]
+    fn test_adaptive_dict_size() {
+        // A few hot values among many distinct ones.
+        let ip_addrs: Vec<u128> = (0..50_000u128)
+            .map(|i| {
+                if i % 2 == 0 {
+                    0xffff_0a00_0000 + (i / 2 * 7) % 64
+                } else {
+                    0xffff_0000_0000 + (i * 2_654_435_761) % (1 << 32)
+                }
+            })
+            .collect();
+        let half_dict_q = HalfDictQ::new(1 << 16).with_adaptive_dict_size(true);
+        let (data, stats) = half_dict_q.encode_with_stats(&ip_addrs).unwrap();
+        assert_eq!(half_dict_q.decode(&data).unwrap(), ip_addrs);
+        assert_eq!(stats.dict_size, Some(64));
+        let fixed = HalfDictQ::new(1 << 16).encode(&ip_addrs).unwrap();
+        assert!(data.len() < fixed.len());
+        test_aux_vals(
+            &HalfDictQ::new(1 << 16)
+                .with_adaptive_dict_size(true)
+                .with_max_counters(256),
+            &ip_addrs,
+        );
+    }
+
+    #[test]
+    fn test_elias_fano_dict() {
+        let ip_addrs: Vec<u128> = (0..20_000u128)
+            .map(|i| 0xffff_0a00_0000 + (i * 7919) % 5000 * 3)
+            .collect();
+        let half_dict_q = HalfDictQ::new(4096).with_elias_fano_dict(true);
+        let (data, stats) = half_dict_q.encode_with_stats(&ip_addrs).unwrap();
+        assert_eq!(half_dict_q.decode(&data).unwrap(), ip_addrs);
+        let (_, stats_delta) = HalfDictQ::new(4096).encode_with_stats(&ip_addrs).unwrap();
+        assert!(stats.header_num_bytes < stats_delta.header_num_bytes);
+        test_aux_vals(&HalfDictQ::new(0).with_elias_fano_dict(true), &ip_addrs);
+        // The format of the dictionary is read from the payload.
+        assert_eq!(HalfDictQ::new(0).decode(&data).unwrap(), ip_addrs);
+        let data = half_dict_q.encode(&ip_addrs[..100]).unwrap();
+        for len in 1..data.len() {
+            assert!(half_dict_q.decode(&data[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_first_large_gaps() {
+        let ip_addrs = &[1_000_000_000u128; 100];
+        test_aux_vals(&HalfDictQ::new(1), ip_addrs);
+        test_aux_vals(&HalfDictQ::new(0), ip_addrs);
+    }
+
+    #[test]
+    fn test_value_out_of_range() {
+        let half_dict_q = HalfDictQ::new(1);
+        assert_eq!(
+            half_dict_q.encode(&[1u128, 1u128, u128::MAX]),
+            Err(IpReprError::ValueOutOfRange(u128::MAX))
+        );
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let half_dict_q = HalfDictQ::new(4);
+        let ip_addrs: Vec<u128> = (0..1000u128).map(|i| (i % 7) * 1_000 + i).collect();
+        let data = half_dict_q.encode(&ip_addrs).unwrap();
+        for len in 1..data.len() {
+            assert!(half_dict_q.decode(&data[..len]).is_err());
+        }
     }
 }
@@ -0,0 +1,204 @@
+use std::collections::BTreeSet;
+
+use fnv::FnvHashMap;
+
+use crate::IPWithCount;
+
+/// Approximate most common values of a column, computed with the Space-Saving
+/// algorithm in a bounded amount of memory.
+///
+/// At most `max_counters` values are tracked. When a new value comes in and all of
+/// the counters are taken, the least frequent value is evicted and the new value
+/// inherits its count, plus one. Counts are therefore overestimated, by at most
+/// `num_vals / max_counters`, and any value more frequent than that is guaranteed
+/// to be tracked.
+///
+/// The number of distinct values is estimated along, from the `max_counters`
+/// smallest hashes of the values (a k-minimum-values sketch).
+#[derive(Debug)]
+pub(crate) struct HeavyHitters {
+    max_counters: usize,
+    counts: FnvHashMap<u128, usize>,
+    /// Same entries as `counts`, ordered by count then value, so that the value
+    /// to evict is the first one.
+    by_count: BTreeSet<(usize, u128)>,
+    /// The `max_counters` smallest hashes of the values.
+    min_hashes: BTreeSet<u64>,
+}
+
+/// Hashes `ip_addr` uniformly over `u64`, with the finalizer of SplitMix64.
+fn hash(ip_addr: u128) -> u64 {
+    let mut hash = (ip_addr as u64) ^ ((ip_addr >> 64) as u64).rotate_left(32);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+impl HeavyHitters {
+    /// Panics if `max_counters` is 0.
+    pub fn new(max_counters: usize) -> HeavyHitters {
+        assert!(max_counters > 0, "at least one counter is required");
+        HeavyHitters {
+            max_counters,
+            counts: FnvHashMap::with_capacity_and_hasher(max_counters, Default::default()),
+            by_count: BTreeSet::new(),
+            min_hashes: BTreeSet::new(),
+        }
+    }
+
+    pub fn from_ip_addrs(ip_addrs: &[u128], max_counters: usize) -> HeavyHitters {
+        let mut heavy_hitters = HeavyHitters::new(max_counters);
+        for &ip_addr in ip_addrs {
+            heavy_hitters.record(ip_addr);
+        }
+        heavy_hitters
+    }
+
+    pub fn record(&mut self, ip_addr: u128) {
+        let hash = hash(ip_addr);
+        if self.min_hashes.len() < self.max_counters {
+            self.min_hashes.insert(hash);
+        } else if hash < *self.min_hashes.last().unwrap() && self.min_hashes.insert(hash) {
+            self.min_hashes.pop_last();
+        }
+        if let Some(count) = self.counts.get_mut(&ip_addr) {
+            self.by_count.remove(&(*count, ip_addr));
+            *count += 1;
+            self.by_count.insert((*count, ip_addr));
+            return;
+        }
+        let mut count = 1;
+        if self.counts.len() == self.max_counters {
+            let (min_count, min_ip_addr) = self.by_count.pop_first().unwrap();
+            self.counts.remove(&min_ip_addr);
+            count += min_count;
+        }
+        self.counts.insert(ip_addr, count);
+        self.by_count.insert((count, ip_addr));
+    }
+
+    /// Returns the `top_n` values with the highest estimated count, in the order
+    /// of `IPWithCount`.
+    pub fn most_common(&self, top_n: usize) -> Vec<IPWithCount> {
+        let mut top_ips: Vec<IPWithCount> = self
+            .by_count
+            .iter()
+            .map(|&(count, ip)| IPWithCount { ip, count })
+            .collect();
+        top_ips.sort_unstable();
+        top_ips.truncate(top_n);
+        top_ips
+    }
+
+    /// Returns the number of distinct values recorded, exact if it is less than
+    /// `max_counters`, estimated otherwise.
+    pub fn num_distinct(&self) -> usize {
+        if self.min_hashes.len() < self.max_counters {
+            return self.min_hashes.len();
+        }
+        // The k-th smallest of n uniform hashes is expected at about k / n of the range.
+        let max_hash = *self.min_hashes.last().unwrap();
+        let estimate = (self.max_counters - 1) as f64 * (u64::MAX as f64 / max_hash as f64);
+        (estimate as usize).max(self.max_counters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fnv::FnvHashSet;
+
+    use super::*;
+    use crate::{tests::random_vals, ValueCounts};
+
+    /// Draws values following a Zipf distribution of exponent `s` over `num_distinct`
+    /// values. The rank of a value is scrambled so that frequent values are not
+    /// the smallest ones.
+    fn zipf_ip_addrs(num_vals: usize, num_distinct: usize, s: f64) -> Vec<u128> {
+        let mut cumulated_weights = Vec::with_capacity(num_distinct);
+        let mut total = 0.0;
+        for rank in 1..=num_distinct {
+            total += 1.0 / (rank as f64).powf(s);
+            cumulated_weights.push(total);
+        }
+        random_vals(num_vals, 42)
+            .map(|random| {
+                let target = (random >> 11) as f64 / (1u64 << 53) as f64 * total;
+                let rank = cumulated_weights.partition_point(|&weight| weight < target);
+                0xffff_0000_0000 + (rank as u128 * 2_654_435_761) % (1 << 32)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_heavy_hitters_exact_when_enough_counters() {
+        let ip_addrs = [1, 2, 2, 3, 3, 3];
+        let heavy_hitters = HeavyHitters::from_ip_addrs(&ip_addrs, 3);
+        let top: Vec<(u128, usize)> = heavy_hitters
+            .most_common(2)
+            .iter()
+            .map(|entry| (entry.ip, entry.count))
+            .collect();
+        assert_eq!(top, vec![(3, 3), (2, 2)]);
+    }
+
+    #[test]
+    fn test_heavy_hitters_eviction() {
+        let heavy_hitters = HeavyHitters::from_ip_addrs(&[1, 1, 1, 2, 3], 2);
+        let top = heavy_hitters.most_common(2);
+        assert_eq!(top.len(), 2);
+        assert_eq!((top[0].ip, top[0].count), (1, 3));
+        // 3 took the counter of 2.
+        assert_eq!((top[1].ip, top[1].count), (3, 2));
+    }
+
+    #[test]
+    fn test_heavy_hitters_num_distinct() {
+        let heavy_hitters = HeavyHitters::from_ip_addrs(&[1, 1, 1, 2, 3], 4);
+        assert_eq!(heavy_hitters.num_distinct(), 3);
+        let ip_addrs = zipf_ip_addrs(200_000, 50_000, 1.1);
+        let num_distinct = ValueCounts::from_ip_addrs(&ip_addrs).num_distinct();
+        let estimate = HeavyHitters::from_ip_addrs(&ip_addrs, 1024).num_distinct();
+        let error = (estimate as f64 / num_distinct as f64 - 1.0).abs();
+        assert!(error < 0.1, "estimate {estimate}, exact {num_distinct}");
+    }
+
+    #[test]
+    fn test_heavy_hitters_recall_on_zipf() {
+        let top_n = 256;
+        let ip_addrs = zipf_ip_addrs(500_000, 100_000, 1.1);
+        let counts = ValueCounts::from_ip_addrs(&ip_addrs);
+        let exact_top: FnvHashSet<u128> = counts
+            .most_common(top_n)
+            .iter()
+            .map(|entry| entry.ip)
+            .collect();
+        assert_eq!(exact_top.len(), top_n);
+        for (max_counters, min_recall) in [(4 * top_n, 0.8), (8 * top_n, 0.95), (16 * top_n, 0.99)]
+        {
+            let heavy_hitters = HeavyHitters::from_ip_addrs(&ip_addrs, max_counters);
+            let num_found = heavy_hitters
+                .most_common(top_n)
+                .iter()
+                .filter(|entry| exact_top.contains(&entry.ip))
+                .count();
+            let recall = num_found as f64 / top_n as f64;
+            assert!(
+                recall >= min_recall,
+                "recall {recall} with {max_counters} counters"
+            );
+        }
+        // The most frequent values are found in the same order.
+        let heavy_hitters = HeavyHitters::from_ip_addrs(&ip_addrs, 16 * top_n);
+        let top_10: Vec<u128> = heavy_hitters
+            .most_common(10)
+            .iter()
+            .map(|entry| entry.ip)
+            .collect();
+        let exact_top_10: Vec<u128> = counts
+            .most_common(10)
+            .iter()
+            .map(|entry| entry.ip)
+            .collect();
+        assert_eq!(top_10, exact_top_10);
+    }
+}
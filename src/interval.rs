@@ -1,8 +1,26 @@
-use std::collections::{BTreeMap, BinaryHeap};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, BinaryHeap},
+    net::IpAddr,
+    ops::{
+        Bound::{Excluded, Unbounded},
+        RangeInclusive,
+    },
+    time::Instant,
+};
 
-use crate::IpRepr;
+use crate::{
+    codec_selection::sample, u128_to_ip_addr, BitSet, Cidr, CodecId, CompressionStats, IpRepr,
+    IpReprError, ValueCounts,
+};
+use fnv::FnvHashSet;
 use tantivy_bitpacker::{self, BitPacker, BitUnpacker};
 
+/// Bitpacks ip addresses in a compact space, obtained by removing the large
+/// unused ranges between them.
+///
+/// The parameter is an additional cost in bits charged for every interval, on
+/// top of its serialized size. It trades compression for a smaller interval table.
 #[derive(Default, Debug)]
 pub struct IntervalEncoding(pub usize);
 
@@ -15,7 +33,7 @@ pub struct IntervalCompressor {
 
 const STOP_BIT: u8 = 128u8;
 
-fn serialize_vint(mut val: u128, output: &mut Vec<u8>) {
+pub(crate) fn serialize_vint(mut val: u128, output: &mut Vec<u8>) {
     loop {
         let next_byte: u8 = (val % 128u128) as u8;
         val /= 128u128;
@@ -28,57 +46,107 @@ fn serialize_vint(mut val: u128, output: &mut Vec<u8>) {
     }
 }
 
-fn deserialize_vint(data: &[u8]) -> (u128, &[u8]) {
+pub(crate) fn deserialize_vint(data: &[u8]) -> Result<(u128, &[u8]), IpReprError> {
     let mut result = 0u128;
     let mut shift = 0u64;
     for i in 0..19 {
-        let b = data[i];
+        let b = *data.get(i).ok_or(IpReprError::TruncatedHeader)?;
+        // The 19th byte only has room for the 2 remaining bits of a u128.
+        if i == 18 && b % 128u8 >= 4 {
+            return Err(IpReprError::InvalidVInt);
+        }
         result |= u128::from(b % 128u8) << shift;
         if b >= STOP_BIT {
-            return (result, &data[i + 1..]);
+            return Ok((result, &data[i + 1..]));
         }
         shift += 7;
     }
-    panic!("invalid data");
+    Err(IpReprError::InvalidVInt)
+}
+
+/// Serializes a sorted list of distinct ip addresses as delta encoded vints.
+pub(crate) fn serialize_sorted_ips(ip_addrs_sorted: &[u128], output: &mut Vec<u8>) {
+    serialize_vint(ip_addrs_sorted.len() as u128, output);
+    let mut prev_ip = 0;
+    for &ip in ip_addrs_sorted {
+        serialize_vint(ip - prev_ip, output);
+        prev_ip = ip;
+    }
+}
+
+pub(crate) fn deserialize_sorted_ips(data: &[u8]) -> Result<(Vec<u128>, &[u8]), IpReprError> {
+    let (num_ips, mut data) = deserialize_vint(data)?;
+    // Every entry takes at least one byte.
+    if num_ips > data.len() as u128 {
+        return Err(IpReprError::TruncatedHeader);
+    }
+    let mut ip_addrs = Vec::with_capacity(num_ips as usize);
+    let mut ip = 0u128;
+    for _ in 0..num_ips {
+        let (delta, new_data) = deserialize_vint(data)?;
+        data = new_data;
+        ip = ip.checked_add(delta).ok_or(IpReprError::InvalidVInt)?;
+        ip_addrs.push(ip);
+    }
+    Ok((ip_addrs, data))
+}
+
+/// Checks that `data` holds `num_vals` values bitpacked with `num_bits`,
+/// including the padding required by `BitUnpacker`.
+pub(crate) fn check_bitpacked_len(
+    num_vals: usize,
+    num_bits: u8,
+    data: &[u8],
+) -> Result<(), IpReprError> {
+    if num_vals == 0 || num_bits == 0 {
+        return Ok(());
+    }
+    let last_addr = (num_vals as u128 - 1) * num_bits as u128 / 8;
+    if last_addr + 8 > data.len() as u128 {
+        return Err(IpReprError::TruncatedData);
+    }
+    Ok(())
 }
 
-fn get_deltas(ip_addrs_sorted: &[u128]) -> BinaryHeap<(u128, usize)> {
-    let mut prev_opt = None;
-    let mut deltas: BinaryHeap<(u128, usize)> = BinaryHeap::new();
+pub(crate) fn deserialize_num_vals(data: &[u8]) -> Result<(usize, &[u8]), IpReprError> {
+    let (num_vals, data) = deserialize_vint(data)?;
+    if num_vals > u32::MAX as u128 {
+        return Err(IpReprError::InvalidNumVals);
+    }
+    Ok((num_vals as usize, data))
+}
+
+/// `BitUnpacker` reads 8 bytes starting at the byte containing the value, so values
+/// that are not byte aligned can only be read back if they fit on 57 bits.
+pub(crate) const MAX_NUM_BITS: u8 = 57;
+
+/// Returns the positions at which starting a new interval removes unused values from
+/// the compact space, with the number of removed values, largest first.
+fn get_gaps(ip_addrs_sorted: &[u128]) -> Vec<(u128, usize)> {
+    let mut prev_opt: Option<u128> = None;
+    let mut gaps: Vec<(u128, usize)> = Vec::new();
     for (pos, ip_addr) in ip_addrs_sorted.iter().cloned().enumerate() {
-        let delta = if let Some(prev) = prev_opt {
-            ip_addr - prev
+        let gap = if let Some(prev) = prev_opt {
+            (ip_addr - prev).saturating_sub(1)
         } else {
-            ip_addr + 1
+            ip_addr
         };
-        deltas.push((delta, pos));
+        if gap > 0 {
+            gaps.push((gap, pos));
+        }
         prev_opt = Some(ip_addr);
     }
-    deltas
+    gaps.sort_unstable_by(|left, right| right.cmp(left));
+    gaps
 }
 
-pub fn train(ip_addrs_sorted: &[u128], add_intervall_cost_in_bits: usize) -> IntervalCompressor {
-    let mut deltas = get_deltas(ip_addrs_sorted);
-    let mut amplitude = *ip_addrs_sorted.last().unwrap() + 1;
-    let mut amplitude_bits: f64 = (amplitude as f64).log2();
-    let mut blanks = Vec::new();
-    while let Some((delta, pos)) = deltas.pop() {
-        let next_amplitude = amplitude - delta + 1;
-        let next_amplitude_bits = (next_amplitude as f64).log2();
-        let gained_bits =
-            ((amplitude_bits - next_amplitude_bits) * ip_addrs_sorted.len() as f64) as usize;
-        if add_intervall_cost_in_bits >= gained_bits {
-            break;
-        }
-        amplitude = next_amplitude;
-        amplitude_bits = next_amplitude_bits;
-        blanks.push(pos);
-    }
+fn vint_num_bytes(val: u128) -> usize {
+    (128 - val.leading_zeros() as usize).div_ceil(7).max(1)
+}
+
+/// Builds the compressor starting a new interval at each of the `blanks` positions.
+fn build_compressor(ip_addrs_sorted: &[u128], mut blanks: Vec<usize>) -> IntervalCompressor {
     blanks.sort();
-    assert!(
-        amplitude <= u64::MAX as u128,
-        "case unsupported for this test program."
-    );
     let mut offset = 0;
     let mut ip_addr_to_compact = BTreeMap::new();
     let mut prev_base = 0;
@@ -93,20 +161,264 @@ pub fn train(ip_addrs_sorted: &[u128], add_intervall_cost_in_bits: usize) -> Int
             prev_base = ip_addr;
         }
     }
-    let num_bits = tantivy_bitpacker::compute_num_bits(amplitude as u64);
-    let compressor = IntervalCompressor {
+    let last_ip_addr = *ip_addrs_sorted.last().unwrap();
+    let max_compact =
+        if let Some((&ip_addr_base, &compact_base)) = ip_addr_to_compact.last_key_value() {
+            compact_base as u128 + (last_ip_addr - ip_addr_base)
+        } else {
+            last_ip_addr
+        };
+    IntervalCompressor {
         ip_addr_to_compact,
-        num_bits,
-    };
-    assert_eq!(
-        compressor.to_compact(*ip_addrs_sorted.last().unwrap()) + 1,
-        amplitude as u64
-    );
-    compressor
+        num_bits: (128 - max_compact.leading_zeros()) as u8,
+    }
+}
+
+/// Trains an `IntervalCompressor` on a non-empty sorted slice.
+///
+/// For each bit width, the minimum number of intervals required to fit the compact
+/// space is obtained by removing the largest gaps first. The bit width with the
+/// smallest total size, interval table included, is selected.
+///
+/// Bit widths are capped at `MAX_NUM_BITS`. Any input can be encoded that way,
+/// since the compact space of n distinct values, with all of the gaps removed, is n.
+pub fn train(ip_addrs_sorted: &[u128], add_intervall_cost_in_bits: usize) -> IntervalCompressor {
+    train_on_distinct(
+        ip_addrs_sorted,
+        ip_addrs_sorted.len(),
+        add_intervall_cost_in_bits,
+    )
+}
+
+/// Same as `train`, on the sorted distinct values of a column of `num_vals` values.
+pub(crate) fn train_on_distinct(
+    ip_addrs_sorted: &[u128],
+    num_vals: usize,
+    add_intervall_cost_in_bits: usize,
+) -> IntervalCompressor {
+    let gaps = get_gaps(ip_addrs_sorted);
+    train_on_gaps(ip_addrs_sorted, gaps, num_vals, add_intervall_cost_in_bits).unwrap()
+}
+
+/// Same as `train`, with the intervals trained on a sample of the non-empty
+/// `ip_addrs`, in memory bounded by the size of the sample.
+///
+/// The values outside of the sample are located with one more pass over `ip_addrs`.
+/// If there are no more distinct ones than values in the sample, the intervals are
+/// trained on both. Otherwise, the gaps of the sample holding some of them are
+/// narrowed to the smallest and largest of those values, the range in between being
+/// kept in the compact space.
+///
+/// Returns `None` if the values outside of the sample are too spread out for the
+/// compact space to fit in `MAX_NUM_BITS`.
+pub(crate) fn train_on_sample(
+    ip_addrs: &[u128],
+    add_intervall_cost_in_bits: usize,
+) -> Option<IntervalCompressor> {
+    let mut ip_addrs_sample = sample(ip_addrs).into_owned();
+    ip_addrs_sample.sort_unstable();
+    ip_addrs_sample.dedup();
+    let max_outside_sample = ip_addrs_sample.len();
+    let mut distinct_outside_sample = Some(FnvHashSet::default());
+    // Smallest and largest values before each value of the sample, and after the
+    // last one.
+    let mut outside_sample: Vec<Option<(u128, u128)>> = vec![None; ip_addrs_sample.len() + 1];
+    for &ip_addr in ip_addrs {
+        let pos = ip_addrs_sample.partition_point(|&val| val < ip_addr);
+        if ip_addrs_sample.get(pos) == Some(&ip_addr) {
+            continue;
+        }
+        if let Some(distinct) = distinct_outside_sample.as_mut() {
+            distinct.insert(ip_addr);
+            if distinct.len() > max_outside_sample {
+                distinct_outside_sample = None;
+            }
+        }
+        let (lo, hi) = outside_sample[pos].get_or_insert((ip_addr, ip_addr));
+        *lo = (*lo).min(ip_addr);
+        *hi = (*hi).max(ip_addr);
+    }
+    if let Some(distinct) = distinct_outside_sample {
+        ip_addrs_sample.extend(distinct);
+        ip_addrs_sample.sort_unstable();
+        return Some(train_on_distinct(
+            &ip_addrs_sample,
+            ip_addrs.len(),
+            add_intervall_cost_in_bits,
+        ));
+    }
+    // Values in compact space, and whether the gap before each of them is empty.
+    let mut ip_addrs_sorted = Vec::with_capacity(3 * outside_sample.len());
+    let mut removable = Vec::with_capacity(3 * outside_sample.len());
+    for (pos, outside) in outside_sample.into_iter().enumerate() {
+        if let Some((lo, hi)) = outside {
+            ip_addrs_sorted.push(lo);
+            removable.push(true);
+            if hi != lo {
+                ip_addrs_sorted.push(hi);
+                removable.push(false);
+            }
+        }
+        if let Some(&ip_addr) = ip_addrs_sample.get(pos) {
+            ip_addrs_sorted.push(ip_addr);
+            removable.push(true);
+        }
+    }
+    let mut gaps = get_gaps(&ip_addrs_sorted);
+    gaps.retain(|&(_, pos)| removable[pos]);
+    train_on_gaps(
+        &ip_addrs_sorted,
+        gaps,
+        ip_addrs.len(),
+        add_intervall_cost_in_bits,
+    )
+}
+
+/// Number of distinct values held in memory at once by `for_each_distinct_sorted`.
+const DISTINCT_CHUNK_LEN: usize = 1 << 16;
+
+/// Calls `f` on each distinct value of `ip_addrs`, in increasing order.
+///
+/// The values are extracted `DISTINCT_CHUNK_LEN` at a time, with one pass over
+/// `ip_addrs` per chunk, so that the column is neither copied nor sorted.
+fn for_each_distinct_sorted(ip_addrs: &[u128], mut f: impl FnMut(u128)) {
+    let mut prev_chunk_last: Option<u128> = None;
+    loop {
+        let mut chunk = BTreeSet::new();
+        for &ip_addr in ip_addrs {
+            if prev_chunk_last.is_some_and(|last| ip_addr <= last) {
+                continue;
+            }
+            if chunk.len() == DISTINCT_CHUNK_LEN
+                && chunk.last().is_some_and(|&last| ip_addr >= last)
+            {
+                continue;
+            }
+            chunk.insert(ip_addr);
+            if chunk.len() > DISTINCT_CHUNK_LEN {
+                chunk.pop_last();
+            }
+        }
+        let Some(&chunk_last) = chunk.last() else {
+            return;
+        };
+        chunk.into_iter().for_each(&mut f);
+        prev_chunk_last = Some(chunk_last);
+    }
+}
+
+/// Same as `train`, on the non-empty `ip_addrs` in any order, in memory bounded by
+/// `max_gaps` rather than by the number of distinct values.
+///
+/// The distinct values are streamed in increasing order, and only the `max_gaps`
+/// largest gaps between them are kept. The intervals are trained on the bounds of
+/// those gaps, the values in between being kept in the compact space. `max_gaps` is
+/// doubled until the compact space fits in `MAX_NUM_BITS`, which it does once all of
+/// the gaps are kept.
+pub(crate) fn train_streaming(
+    ip_addrs: &[u128],
+    max_gaps: usize,
+    add_intervall_cost_in_bits: usize,
+) -> IntervalCompressor {
+    let mut max_gaps = max_gaps.max(1);
+    loop {
+        if let Some(compressor) =
+            train_on_largest_gaps(ip_addrs, max_gaps, add_intervall_cost_in_bits)
+        {
+            return compressor;
+        }
+        max_gaps *= 2;
+    }
+}
+
+fn train_on_largest_gaps(
+    ip_addrs: &[u128],
+    max_gaps: usize,
+    add_intervall_cost_in_bits: usize,
+) -> Option<IntervalCompressor> {
+    let mut first_last: Option<(u128, u128)> = None;
+    // The largest gaps, as `(gap, value before, value after)`, smallest on top.
+    let mut largest_gaps = BinaryHeap::with_capacity(max_gaps + 1);
+    for_each_distinct_sorted(ip_addrs, |ip_addr| {
+        let (_, last) = first_last.get_or_insert((ip_addr, ip_addr));
+        let gap = (ip_addr - *last).saturating_sub(1);
+        if gap > 0 {
+            largest_gaps.push(Reverse((gap, *last, ip_addr)));
+            if largest_gaps.len() > max_gaps {
+                largest_gaps.pop();
+            }
+        }
+        *last = ip_addr;
+    });
+    let (first, last) = first_last?;
+    // Values in compact space, and whether the gap before each of them is empty.
+    let mut points = BTreeMap::new();
+    points.insert(first, true);
+    for Reverse((_, before, after)) in largest_gaps {
+        points.entry(before).or_insert(false);
+        points.insert(after, true);
+    }
+    points.entry(last).or_insert(false);
+    let (ip_addrs_sorted, removable): (Vec<u128>, Vec<bool>) = points.into_iter().unzip();
+    let mut gaps = get_gaps(&ip_addrs_sorted);
+    gaps.retain(|&(_, pos)| removable[pos]);
+    train_on_gaps(
+        &ip_addrs_sorted,
+        gaps,
+        ip_addrs.len(),
+        add_intervall_cost_in_bits,
+    )
+}
+
+/// Trains an `IntervalCompressor` removing some of `gaps`, as returned by
+/// `get_gaps`. Returns `None` if no bit width up to `MAX_NUM_BITS` fits.
+fn train_on_gaps(
+    ip_addrs_sorted: &[u128],
+    gaps: Vec<(u128, usize)>,
+    num_vals: usize,
+    add_intervall_cost_in_bits: usize,
+) -> Option<IntervalCompressor> {
+    let last_ip_addr = *ip_addrs_sorted.last().unwrap();
+    let mut cumulated_gaps = Vec::with_capacity(gaps.len() + 1);
+    cumulated_gaps.push(0u128);
+    for &(gap, _) in &gaps {
+        cumulated_gaps.push(cumulated_gaps.last().unwrap() + gap);
+    }
+
+    let mut best: Option<(usize, IntervalCompressor)> = None;
+    let mut prev_num_intervals = None;
+    // Going from the widest to the narrowest bit width, so that ties are won
+    // by the compressor with the fewest intervals.
+    for num_bits in (0..=MAX_NUM_BITS).rev() {
+        let max_compact = (1u128 << num_bits) - 1;
+        let required_gaps = last_ip_addr.saturating_sub(max_compact);
+        let num_intervals = cumulated_gaps.partition_point(|&cumulated| cumulated < required_gaps);
+        if num_intervals == cumulated_gaps.len() {
+            break;
+        }
+        // Same intervals as with the previous bit width.
+        if prev_num_intervals == Some(num_intervals) {
+            continue;
+        }
+        prev_num_intervals = Some(num_intervals);
+        let blanks = gaps[..num_intervals].iter().map(|&(_, pos)| pos).collect();
+        let compressor = build_compressor(ip_addrs_sorted, blanks);
+        let payload_num_bytes = (num_vals * compressor.num_bits as usize).div_ceil(8);
+        let cost_in_bits = 8 * (compressor.header_num_bytes() + payload_num_bytes)
+            + num_intervals * add_intervall_cost_in_bits;
+        if best
+            .as_ref()
+            .map(|(best_cost_in_bits, _)| cost_in_bits < *best_cost_in_bits)
+            .unwrap_or(true)
+        {
+            best = Some((cost_in_bits, compressor));
+        }
+    }
+    best.map(|(_, compressor)| compressor)
 }
 
 impl IntervalCompressor {
-    fn to_compact(&self, ip_addr: u128) -> u64 {
+    pub(crate) fn to_compact(&self, ip_addr: u128) -> u64 {
         if let Some((&ip_addr_base, &compact_base)) =
             self.ip_addr_to_compact.range(..=ip_addr).last()
         {
@@ -116,7 +428,25 @@ impl IntervalCompressor {
         }
     }
 
-    fn write_header(&self, output: &mut Vec<u8>) {
+    pub fn num_intervals(&self) -> usize {
+        self.ip_addr_to_compact.len()
+    }
+
+    /// Returns the number of bytes written by `write_header`.
+    fn header_num_bytes(&self) -> usize {
+        let mut num_bytes = vint_num_bytes(self.ip_addr_to_compact.len() as u128) + 1;
+        let mut prev_ip = 0;
+        let mut prev_compact = 0;
+        for (&ip, &compact) in &self.ip_addr_to_compact {
+            num_bytes += vint_num_bytes(ip - prev_ip);
+            num_bytes += vint_num_bytes((compact - prev_compact) as u128);
+            prev_ip = ip;
+            prev_compact = compact;
+        }
+        num_bytes
+    }
+
+    pub(crate) fn write_header(&self, output: &mut Vec<u8>) {
         assert!(output.is_empty());
         serialize_vint(self.ip_addr_to_compact.len() as u128, output);
         let mut prev_ip = 0;
@@ -124,22 +454,22 @@ impl IntervalCompressor {
         for (&ip, &compact) in &self.ip_addr_to_compact {
             let delta_ip = ip - prev_ip;
             let delta_compact = compact - prev_compact;
-            serialize_vint(delta_ip as u128, output);
+            serialize_vint(delta_ip, output);
             serialize_vint(delta_compact as u128, output);
             prev_ip = ip;
             prev_compact = compact;
         }
         output.push(self.num_bits);
-        println!("NumIntervals\t{}", self.ip_addr_to_compact.len());
-        println!("HeaderLen\t{}", output.len());
-        let bits_per_interval = output.len() as f64 / self.ip_addr_to_compact.len() as f64;
-        println!("BitsPerInterval\t{}", bits_per_interval);
     }
 
-    pub fn compress(&self, ip_addrs: &[u128]) -> Vec<u8> {
+    pub fn compress(&self, ip_addrs: &[u128], stats: &mut CompressionStats) -> Vec<u8> {
         let mut output: Vec<u8> = Vec::new();
         self.write_header(&mut output);
         serialize_vint(ip_addrs.len() as u128, &mut output);
+        stats.num_vals = ip_addrs.len();
+        stats.header_num_bytes = output.len();
+        stats.num_intervals = Some(self.ip_addr_to_compact.len());
+        stats.num_bits = Some(self.num_bits);
         let mut bitpacker = BitPacker::default();
         for &ip_addr in ip_addrs {
             let compact = self.to_compact(ip_addr);
@@ -148,41 +478,93 @@ impl IntervalCompressor {
                 .unwrap();
         }
         bitpacker.close(&mut output).unwrap();
+        stats.payload_num_bytes = output.len() - stats.header_num_bytes;
         output
     }
 }
 
+#[derive(Default)]
 pub struct IntervallDecompressor {
     compact_to_ip_addrs: BTreeMap<u64, u128>,
+    ip_addr_to_compact: BTreeMap<u128, u64>,
+    pub(crate) num_bits: u8,
+    /// Largest compact value mapping into the `u128` domain.
+    max_compact: u64,
     bit_unpacker: BitUnpacker,
 }
 
 impl IntervallDecompressor {
-    fn open(mut data: &[u8]) -> (IntervallDecompressor, &[u8]) {
-        let (num_ip_addrs, new_data) = deserialize_vint(data);
+    pub(crate) fn open(mut data: &[u8]) -> Result<(IntervallDecompressor, &[u8]), IpReprError> {
+        let (num_ip_addrs, new_data) = deserialize_vint(data)?;
         data = new_data;
         let mut ip_addr = 0u128;
         let mut compact = 0u64;
         let mut compact_to_ip_addrs: BTreeMap<u64, u128> = Default::default();
-        for _ in 0..num_ip_addrs {
-            let (ip_addr_delta, new_data) = deserialize_vint(data);
+        for i in 0..num_ip_addrs {
+            let (ip_addr_delta, new_data) = deserialize_vint(data)?;
             data = new_data;
-            let (compact_delta, new_data) = deserialize_vint(data);
+            let (compact_delta, new_data) = deserialize_vint(data)?;
             data = new_data;
-            ip_addr += ip_addr_delta;
-            compact += compact_delta as u64;
+            // Intervals have to be strictly increasing, and cannot overlap in
+            // the ip space.
+            if i > 0 && (compact_delta == 0 || compact_delta > ip_addr_delta) {
+                return Err(IpReprError::NonMonotonicIntervals);
+            }
+            ip_addr = ip_addr
+                .checked_add(ip_addr_delta)
+                .ok_or(IpReprError::NonMonotonicIntervals)?;
+            compact = u64::try_from(compact_delta)
+                .ok()
+                .and_then(|compact_delta| compact.checked_add(compact_delta))
+                .ok_or(IpReprError::NonMonotonicIntervals)?;
+            if i == 0 && compact as u128 > ip_addr {
+                return Err(IpReprError::NonMonotonicIntervals);
+            }
             compact_to_ip_addrs.insert(compact, ip_addr);
         }
-        let num_bits = data[0];
-        data = &data[1..];
+        let (&num_bits, new_data) = data.split_first().ok_or(IpReprError::TruncatedHeader)?;
+        data = new_data;
+        if num_bits > MAX_NUM_BITS {
+            return Err(IpReprError::InvalidNumBits(num_bits));
+        }
+        let mut max_compact = (1u64 << num_bits) - 1;
+        if let Some((&compact_base, &ip_base)) = compact_to_ip_addrs.iter().next_back() {
+            if max_compact < compact_base {
+                return Err(IpReprError::NonMonotonicIntervals);
+            }
+            // The last interval has to map into the u128 domain. The compact space
+            // is sized on the largest compact value, which requires all of its
+            // `num_bits` bits, so only the values above it may fall outside.
+            let min_max_compact = max_compact >> 1;
+            let num_in_domain = u128::MAX - ip_base;
+            if min_max_compact > compact_base
+                && num_in_domain < (min_max_compact - compact_base) as u128
+            {
+                return Err(IpReprError::NonMonotonicIntervals);
+            }
+            if num_in_domain < (max_compact - compact_base) as u128 {
+                max_compact = compact_base + num_in_domain as u64;
+            }
+        }
+        let ip_addr_to_compact = compact_to_ip_addrs
+            .iter()
+            .map(|(&compact, &ip_addr)| (ip_addr, compact))
+            .collect();
         let decompressor = IntervallDecompressor {
             compact_to_ip_addrs,
+            ip_addr_to_compact,
+            num_bits,
+            max_compact,
             bit_unpacker: BitUnpacker::new(num_bits),
         };
-        (decompressor, data)
+        Ok((decompressor, data))
     }
 
-    fn compact_to_ip_addr(&self, compact: u64) -> u128 {
+    /// Maps `compact` back to its ip address.
+    ///
+    /// `compact` has to be at most `max_compact`, which holds for any value written by
+    /// the encoder. Values read from untrusted data go through `checked_compact_to_ip_addr`.
+    pub(crate) fn compact_to_ip_addr(&self, compact: u64) -> u128 {
         if let Some((&compact_base, &ip_base)) = self.compact_to_ip_addrs.range(..=compact).last() {
             ip_base + (compact - compact_base) as u128
         } else {
@@ -190,10 +572,72 @@ impl IntervallDecompressor {
         }
     }
 
+    /// Same as `compact_to_ip_addr`, returning `CorruptedData` if `compact` maps outside
+    /// of the `u128` domain.
+    pub(crate) fn checked_compact_to_ip_addr(&self, compact: u64) -> Result<u128, IpReprError> {
+        if compact > self.max_compact {
+            return Err(IpReprError::CorruptedData);
+        }
+        Ok(self.compact_to_ip_addr(compact))
+    }
+
     pub fn get(&self, idx: usize, data: &[u8]) -> u128 {
         let base = self.bit_unpacker.get(idx as u64, data);
         self.compact_to_ip_addr(base)
     }
+
+    /// Same as `get`, returning `CorruptedData` if the value maps outside of the
+    /// `u128` domain.
+    pub(crate) fn checked_get(&self, idx: usize, data: &[u8]) -> Result<u128, IpReprError> {
+        let base = self.bit_unpacker.get(idx as u64, data);
+        self.checked_compact_to_ip_addr(base)
+    }
+
+    /// Returns the interval containing `ip_addr`, or the last one before it, as
+    /// `(ip_base, compact_base, len)`.
+    ///
+    /// Values below the first interval are mapped on themselves, which we
+    /// represent as an implicit interval starting at 0.
+    fn interval_for_ip_addr(&self, ip_addr: u128) -> (u128, u64, u128) {
+        let (ip_base, compact_base, next_compact_base) =
+            match self.ip_addr_to_compact.range(..=ip_addr).next_back() {
+                Some((&ip_base, &compact_base)) => {
+                    let next_compact_base = self
+                        .compact_to_ip_addrs
+                        .range((Excluded(compact_base), Unbounded))
+                        .next()
+                        .map(|(&compact, _)| compact);
+                    (ip_base, compact_base, next_compact_base)
+                }
+                None => (0, 0, self.compact_to_ip_addrs.keys().next().cloned()),
+            };
+        let compact_end = next_compact_base
+            .map(|compact| compact as u128)
+            .unwrap_or(self.max_compact as u128 + 1);
+        let len = compact_end.saturating_sub(compact_base as u128);
+        (ip_base, compact_base, len)
+    }
+
+    /// Translates a range of ip addresses into the matching range of compact values.
+    ///
+    /// Since `to_compact` is monotonic, this is a single range. Returns `None` if the
+    /// range only covers blanks.
+    pub(crate) fn compact_range(&self, range: RangeInclusive<u128>) -> Option<RangeInclusive<u64>> {
+        let (&lo, &hi) = (range.start(), range.end());
+        if lo > hi {
+            return None;
+        }
+        // Smallest compact value mapping to an ip >= lo.
+        let (ip_base, compact_base, len) = self.interval_for_ip_addr(lo);
+        let compact_lo = compact_base as u128 + (lo - ip_base).min(len);
+        // One past the largest compact value mapping to an ip <= hi.
+        let (ip_base, compact_base, len) = self.interval_for_ip_addr(hi);
+        let compact_hi = compact_base as u128 + (hi - ip_base).saturating_add(1).min(len);
+        if compact_lo >= compact_hi {
+            return None;
+        }
+        Some(compact_lo as u64..=(compact_hi - 1) as u64)
+    }
 }
 
 impl IntervalEncoding {
@@ -205,33 +649,184 @@ impl IntervalEncoding {
 }
 
 impl IpRepr for IntervalEncoding {
-    fn encode(&self, ip_addrs: &[u128]) -> Vec<u8> {
+    fn codec_id(&self) -> CodecId {
+        CodecId::Interval
+    }
+
+    fn params(&self) -> Vec<u64> {
+        vec![self.0 as u64]
+    }
+
+    fn encode_with_stats(
+        &self,
+        ip_addrs: &[u128],
+    ) -> Result<(Vec<u8>, CompressionStats), IpReprError> {
+        let mut stats = CompressionStats::default();
         if ip_addrs.is_empty() {
-            return Vec::new();
+            return Ok((Vec::new(), stats));
         }
+        let start = Instant::now();
         let compressor = self.train(ip_addrs);
-        compressor.compress(ip_addrs)
+        stats.train_time = start.elapsed();
+        let start = Instant::now();
+        let compressed = compressor.compress(ip_addrs, &mut stats);
+        stats.compress_time = start.elapsed();
+        Ok((compressed, stats))
+    }
+
+    fn encode_with_counts(
+        &self,
+        ip_addrs: &[u128],
+        counts: &ValueCounts,
+    ) -> Result<(Vec<u8>, CompressionStats), IpReprError> {
+        let mut stats = CompressionStats::default();
+        if ip_addrs.is_empty() {
+            return Ok((Vec::new(), stats));
+        }
+        let start = Instant::now();
+        let compressor = train_on_distinct(&counts.sorted_distinct(), counts.num_vals(), self.0);
+        stats.train_time = start.elapsed();
+        let start = Instant::now();
+        let compressed = compressor.compress(ip_addrs, &mut stats);
+        stats.compress_time = start.elapsed();
+        Ok((compressed, stats))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u128>, IpReprError> {
+        let reader = IntervalReader::open(data)?;
+        Ok(reader.iter().collect())
+    }
+
+    fn matches_cidr(&self, data: &[u8], cidrs: &[Cidr]) -> Result<BitSet, IpReprError> {
+        let reader = IntervalReader::open(data)?;
+        Ok(reader.matches_cidr(cidrs))
+    }
+}
+
+/// Random access reader over a column encoded with `IntervalEncoding`.
+///
+/// The header is parsed once in `open`, after which each value can be
+/// fetched in O(1) without decoding the rest of the column.
+pub struct IntervalReader<'a> {
+    decompressor: IntervallDecompressor,
+    num_vals: u32,
+    data: &'a [u8],
+}
+
+impl<'a> IntervalReader<'a> {
+    /// Returns `CorruptedData` if any value of the column maps outside of the `u128` domain.
+    pub fn open(data: &'a [u8]) -> Result<IntervalReader<'a>, IpReprError> {
+        if data.is_empty() {
+            return Ok(IntervalReader {
+                decompressor: IntervallDecompressor::default(),
+                num_vals: 0,
+                data,
+            });
+        }
+        let (decompressor, data) = IntervallDecompressor::open(data)?;
+        let (num_vals, data) = deserialize_num_vals(data)?;
+        check_bitpacked_len(num_vals, decompressor.num_bits, data)?;
+        // Checked once here, so the accessors below can map values without overflowing.
+        for row in 0..num_vals {
+            decompressor.checked_get(row, data)?;
+        }
+        Ok(IntervalReader {
+            decompressor,
+            num_vals: num_vals as u32,
+            data,
+        })
+    }
+
+    /// Returns the value at `row`.
+    ///
+    /// Panics if `row >= self.len()`.
+    pub fn get(&self, row: u32) -> u128 {
+        assert!(row < self.num_vals, "row {row} out of bounds");
+        self.decompressor.get(row as usize, self.data)
+    }
+
+    /// Returns the ip address at `row`, IPv4-mapped addresses being returned as IPv4.
+    ///
+    /// Panics if `row` is out of bounds.
+    pub fn get_ip(&self, row: u32) -> IpAddr {
+        u128_to_ip_addr(self.get(row))
+    }
+
+    /// Fills `output` with the values starting at row `start`.
+    ///
+    /// Panics if the range goes past the end of the column.
+    pub fn get_range(&self, start: u32, output: &mut [u128]) {
+        assert!(
+            start as usize + output.len() <= self.num_vals as usize,
+            "range out of bounds"
+        );
+        for (row, out) in (start as usize..).zip(output.iter_mut()) {
+            *out = self.decompressor.get(row, self.data);
+        }
+    }
+
+    /// Returns the rows whose value is within `range`, in increasing order.
+    ///
+    /// The range is translated into compact space, so the bitpacked values are
+    /// compared directly without mapping them back to ip addresses.
+    pub fn get_row_ids_for_value_range(&self, range: RangeInclusive<u128>) -> Vec<u32> {
+        let mut row_ids = Vec::new();
+        if let Some(compact_range) = self.decompressor.compact_range(range) {
+            for row in 0..self.num_vals {
+                let compact = self.decompressor.bit_unpacker.get(row as u64, self.data);
+                if compact_range.contains(&compact) {
+                    row_ids.push(row);
+                }
+            }
+        }
+        row_ids
     }
 
-    fn decode(&self, data: &[u8]) -> Vec<u128> {
-        let (decompressor, data) = IntervallDecompressor::open(data);
-        let (num_vals, data) = deserialize_vint(data);
-        let mut ip_addrs = Vec::new();
-        for idx in 0..num_vals as usize {
-            let ip_addr = decompressor.get(idx, data);
-            ip_addrs.push(ip_addr);
+    /// Returns the rows whose value is in any of `cidrs`, scanning the column once.
+    pub fn matches_cidr(&self, cidrs: &[Cidr]) -> BitSet {
+        let mut bitset = BitSet::with_max_value(self.num_vals);
+        let compact_ranges: Vec<RangeInclusive<u64>> = cidrs
+            .iter()
+            .flat_map(|cidr| self.decompressor.compact_range(cidr.range()))
+            .collect();
+        if compact_ranges.is_empty() {
+            return bitset;
+        }
+        for row in 0..self.num_vals {
+            let compact = self.decompressor.bit_unpacker.get(row as u64, self.data);
+            if compact_ranges
+                .iter()
+                .any(|compact_range| compact_range.contains(&compact))
+            {
+                bitset.insert(row);
+            }
         }
-        ip_addrs
+        bitset
+    }
+
+    pub fn len(&self) -> u32 {
+        self.num_vals
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_vals == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u128> + '_ {
+        (0..self.num_vals as usize).map(move |row| self.decompressor.get(row, self.data))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::str::FromStr;
+
     use super::*;
+    use crate::ip_addr_to_u128;
 
     fn test_aux_vals<T: IpRepr>(ip_repr: &T, ip_addrs: &[u128]) {
-        let data = ip_repr.encode(ip_addrs);
-        let decoded_ip_addrs = ip_repr.decode(&data);
+        let data = ip_repr.encode(ip_addrs).unwrap();
+        let decoded_ip_addrs = ip_repr.decode(&data).unwrap();
         assert_eq!(&decoded_ip_addrs, ip_addrs);
     }
 
@@ -252,10 +847,339 @@ mod tests {
         test_aux_vals(&interval_encoding, ip_addrs)
     }
 
+    #[test]
+    fn test_train_on_sample() {
+        // A few values far away from the others, outside of the sample.
+        let mut ip_addrs: Vec<u128> = (0..200_000u128)
+            .map(|i| 0xffff_0a00_0000 + (i * 7919) % 100_000)
+            .collect();
+        ip_addrs[1500] = 1 << 100;
+        ip_addrs[1501] = (1 << 100) + 20;
+        ip_addrs[1502] = 3;
+        ip_addrs[150_000] = u128::MAX;
+        // Too many distinct values outside of the sample to be all kept.
+        let dense_ip_addrs: Vec<u128> = (0..200_000u128).map(|i| 0xffff_0a00_0000 + i).collect();
+        for ip_addrs in [ip_addrs, dense_ip_addrs] {
+            let compressor = train_on_sample(&ip_addrs, 64).unwrap();
+            let data = compressor.compress(&ip_addrs, &mut CompressionStats::default());
+            assert_eq!(IntervalEncoding::default().decode(&data).unwrap(), ip_addrs);
+            let mut ip_addrs_sorted = ip_addrs.clone();
+            ip_addrs_sorted.sort();
+            assert_eq!(compressor.num_bits, train(&ip_addrs_sorted, 64).num_bits);
+        }
+
+        // Values outside of the sample spread over the whole space.
+        let ip_addrs: Vec<u128> = (0..200_000u128).map(|i| i << 100).collect();
+        assert!(train_on_sample(&ip_addrs, 64).is_none());
+    }
+
+    #[test]
+    fn test_train_streaming() {
+        let mut ip_addrs: Vec<u128> = (0..200_000u128)
+            .map(|i| 0xffff_0a00_0000 + (i * 7919) % 100_000)
+            .collect();
+        ip_addrs[1500] = 1 << 100;
+        ip_addrs[1502] = 3;
+        ip_addrs[150_000] = u128::MAX;
+        let spread_ip_addrs: Vec<u128> = (0..20_000u128).rev().map(|i| i << 100).collect();
+        for ip_addrs in [ip_addrs, spread_ip_addrs] {
+            let mut ip_addrs_sorted = ip_addrs.clone();
+            ip_addrs_sorted.sort();
+            let expected = train(&ip_addrs_sorted, 64);
+            // All of the gaps are kept.
+            let compressor = train_streaming(&ip_addrs, ip_addrs.len(), 64);
+            assert_eq!(compressor.ip_addr_to_compact, expected.ip_addr_to_compact);
+            for max_gaps in [1, 16] {
+                let compressor = train_streaming(&ip_addrs, max_gaps, 64);
+                let data = compressor.compress(&ip_addrs, &mut CompressionStats::default());
+                assert_eq!(IntervalEncoding::default().decode(&data).unwrap(), ip_addrs);
+            }
+        }
+    }
+
     #[test]
     fn test_first_large_gaps() {
         let ip_addrs = &[1_000_000_000u128; 100];
         let interval_encoding = IntervalEncoding::default();
         test_aux_vals(&interval_encoding, ip_addrs)
     }
+
+    #[test]
+    fn test_deserialize_vint_errors() {
+        assert_eq!(deserialize_vint(&[]), Err(IpReprError::TruncatedHeader));
+        assert_eq!(deserialize_vint(&[1, 2]), Err(IpReprError::TruncatedHeader));
+        assert_eq!(deserialize_vint(&[0; 19]), Err(IpReprError::InvalidVInt));
+        let mut data = vec![0; 18];
+        data.push(STOP_BIT | 4);
+        assert_eq!(deserialize_vint(&data), Err(IpReprError::InvalidVInt));
+        let mut output = Vec::new();
+        serialize_vint(u128::MAX, &mut output);
+        assert_eq!(deserialize_vint(&output).unwrap().0, u128::MAX);
+    }
+
+    #[test]
+    fn test_decode_invalid_data() {
+        let interval_encoding = IntervalEncoding::default();
+        let ip_addrs = &[1u128, 100u128, 3u128, 99999u128, 4_000_211_221u128];
+        let data = interval_encoding.encode(ip_addrs).unwrap();
+        for len in 1..data.len() {
+            assert!(interval_encoding.decode(&data[..len]).is_err());
+        }
+        // num_bits > 64
+        assert_eq!(
+            interval_encoding.decode(&[STOP_BIT, 65]),
+            Err(IpReprError::InvalidNumBits(65))
+        );
+        // Unaligned values wider than 57 bits cannot be read by the `BitUnpacker`.
+        assert_eq!(
+            interval_encoding.decode(&[STOP_BIT, 58]),
+            Err(IpReprError::InvalidNumBits(58))
+        );
+        // The compact space of the last interval goes past u128::MAX.
+        let mut data = Vec::new();
+        serialize_vint(1, &mut data);
+        serialize_vint(u128::MAX - 1, &mut data);
+        serialize_vint(0, &mut data);
+        data.push(8);
+        assert_eq!(
+            interval_encoding.decode(&data),
+            Err(IpReprError::NonMonotonicIntervals)
+        );
+        // Second interval starts before the first one.
+        let mut data = Vec::new();
+        serialize_vint(2, &mut data);
+        serialize_vint(10, &mut data);
+        serialize_vint(0, &mut data);
+        serialize_vint(0, &mut data);
+        serialize_vint(1, &mut data);
+        data.push(8);
+        assert_eq!(
+            interval_encoding.decode(&data),
+            Err(IpReprError::NonMonotonicIntervals)
+        );
+    }
+
+    #[test]
+    fn test_decode_compact_outside_of_domain() {
+        let interval_encoding = IntervalEncoding::default();
+        // The compact space is 0..8, of which 5 and above map past u128::MAX.
+        let ip_addrs = &[0, 1, 2, u128::MAX - 1, u128::MAX];
+        let mut data = interval_encoding.encode(ip_addrs).unwrap();
+        assert_eq!(&interval_encoding.decode(&data).unwrap(), ip_addrs);
+        let (decompressor, rest) = IntervallDecompressor::open(&data).unwrap();
+        assert_eq!(decompressor.num_bits, 3);
+        let (_, rest) = deserialize_num_vals(rest).unwrap();
+        let payload_start = data.len() - rest.len();
+        data[payload_start] |= 0b111;
+        assert_eq!(
+            interval_encoding.decode(&data),
+            Err(IpReprError::CorruptedData)
+        );
+        assert!(matches!(
+            IntervalReader::open(&data),
+            Err(IpReprError::CorruptedData)
+        ));
+    }
+
+    #[test]
+    fn test_decode_arbitrary_bytes_does_not_panic() {
+        let interval_encoding = IntervalEncoding::default();
+        let mut state = 42u64;
+        for len in 0..200 {
+            let data: Vec<u8> = (0..len)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    (state >> 56) as u8
+                })
+                .collect();
+            let _ = interval_encoding.decode(&data);
+        }
+    }
+
+    #[test]
+    fn test_interval_reader() {
+        let ip_addrs: Vec<u128> = (0..1000u128)
+            .map(|i| if i % 2 == 0 { i * 3 } else { 1_000_000_000 + i })
+            .collect();
+        let data = IntervalEncoding::default().encode(&ip_addrs).unwrap();
+        let reader = IntervalReader::open(&data).unwrap();
+        assert_eq!(reader.len(), 1000);
+        assert!(!reader.is_empty());
+        for (row, &ip_addr) in ip_addrs.iter().enumerate() {
+            assert_eq!(reader.get(row as u32), ip_addr);
+        }
+        let mut output = vec![0u128; 10];
+        reader.get_range(990, &mut output);
+        assert_eq!(&output[..], &ip_addrs[990..]);
+        assert_eq!(reader.iter().collect::<Vec<_>>(), ip_addrs);
+    }
+
+    #[test]
+    fn test_interval_reader_get_ip() {
+        let ip_addrs: Vec<IpAddr> = ["10.0.0.1", "2001:db8::1", "192.168.0.1"]
+            .iter()
+            .map(|ip_addr| IpAddr::from_str(ip_addr).unwrap())
+            .collect();
+        let vals: Vec<u128> = ip_addrs.iter().cloned().map(ip_addr_to_u128).collect();
+        let data = IntervalEncoding::default().encode(&vals).unwrap();
+        let reader = IntervalReader::open(&data).unwrap();
+        for (row, &ip_addr) in ip_addrs.iter().enumerate() {
+            assert_eq!(reader.get_ip(row as u32), ip_addr);
+        }
+    }
+
+    #[test]
+    fn test_interval_reader_empty() {
+        let reader = IntervalReader::open(&[]).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(reader.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_get_row_ids_for_value_range() {
+        let ip_addrs: Vec<u128> = (0..2000u128)
+            .map(|i| match i % 4 {
+                0 => i,
+                1 => 1_000_000 + i,
+                2 => 4_000_211_221 + i % 7,
+                _ => 0xffff_0000_0000 + i * 1000,
+            })
+            .collect();
+        let data = IntervalEncoding::default().encode(&ip_addrs).unwrap();
+        let reader = IntervalReader::open(&data).unwrap();
+        let bounds = [
+            0u128,
+            1,
+            4,
+            1_999,
+            2_000,
+            500_000,
+            1_000_001,
+            1_001_999,
+            4_000_211_220,
+            4_000_211_221,
+            4_000_211_224,
+            4_000_211_227,
+            0xffff_0000_0000,
+            0xffff_0000_0000 + 999_999,
+            0xffff_0000_0000 + 3_000_000,
+            u128::MAX,
+        ];
+        for &lo in &bounds {
+            for &hi in &bounds {
+                let expected: Vec<u32> = ip_addrs
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, ip_addr)| (lo..=hi).contains(*ip_addr))
+                    .map(|(row, _)| row as u32)
+                    .collect();
+                assert_eq!(
+                    reader.get_row_ids_for_value_range(lo..=hi),
+                    expected,
+                    "{lo}..={hi}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_value_range_in_blank() {
+        let ip_addrs = &[
+            1_000_000u128,
+            1_000_001u128,
+            1_000_000_000_000u128,
+            1_000_000_000_001u128,
+        ];
+        let data = IntervalEncoding::default().encode(ip_addrs).unwrap();
+        let reader = IntervalReader::open(&data).unwrap();
+        assert!(reader
+            .decompressor
+            .compact_range(1_000_002..=999_999_999_999)
+            .is_none());
+        assert!(reader.decompressor.compact_range(0..=999_999).is_none());
+        assert_eq!(
+            reader.get_row_ids_for_value_range(1_000_001..=1_000_000_000_000),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn test_large_amplitude() {
+        let mut state = 7u128;
+        let mut ip_addrs: Vec<u128> = (0..1000)
+            .map(|i| {
+                state = state
+                    .wrapping_mul(0x2360_ed05_1fc6_5da4_4385_df64_9fcc_f645)
+                    .wrapping_add(0x5851_f42d_4c95_7f2d_1405_7b7e_f767_814f);
+                if i % 2 == 0 {
+                    0xffff_0000_0000 + (state >> 96)
+                } else {
+                    state
+                }
+            })
+            .collect();
+        ip_addrs.push(0);
+        ip_addrs.push(u128::MAX);
+        ip_addrs.push(u128::MAX);
+        for cost in [0, 64, 1 << 20] {
+            let interval_encoding = IntervalEncoding(cost);
+            test_aux_vals(&interval_encoding, &ip_addrs);
+            let compressor = interval_encoding.train(&ip_addrs);
+            assert!(compressor.num_bits <= MAX_NUM_BITS);
+        }
+    }
+
+    #[test]
+    fn test_extreme_values() {
+        let interval_encoding = IntervalEncoding(1 << 20);
+        test_aux_vals(&interval_encoding, &[u128::MAX]);
+        test_aux_vals(&interval_encoding, &[0, u128::MAX]);
+        test_aux_vals(&interval_encoding, &[u128::MAX - 1, u128::MAX, 0, 1]);
+        test_aux_vals(&interval_encoding, &[1u128 << 64, 0]);
+    }
+
+    #[test]
+    fn test_header_num_bytes() {
+        let ip_addrs = &[1u128, 100, 3, 99999, 100000, 100001, 4_000_211_221, 333];
+        let mut ip_addrs_sorted = ip_addrs.to_vec();
+        ip_addrs_sorted.sort();
+        let gaps = get_gaps(&ip_addrs_sorted);
+        for num_intervals in 0..=gaps.len() {
+            let blanks = gaps[..num_intervals].iter().map(|&(_, pos)| pos).collect();
+            let compressor = build_compressor(&ip_addrs_sorted, blanks);
+            let mut output = Vec::new();
+            compressor.write_header(&mut output);
+            assert_eq!(compressor.header_num_bytes(), output.len());
+        }
+    }
+
+    #[test]
+    fn test_train_is_optimal() {
+        let mut state = 3u64;
+        let mut ip_addrs: Vec<u128> = (0..2000)
+            .map(|i| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                let cluster = (state >> 61) as u128;
+                cluster * 1_000_000_000 + (i % (1 << cluster)) as u128 * 17
+            })
+            .collect();
+        ip_addrs.push(u64::MAX as u128);
+        let mut ip_addrs_sorted = ip_addrs.clone();
+        ip_addrs_sorted.sort();
+        let gaps = get_gaps(&ip_addrs_sorted);
+        let num_bytes = IntervalEncoding(0).encode(&ip_addrs).unwrap().len();
+        for num_intervals in 0..=gaps.len() {
+            let blanks = gaps[..num_intervals].iter().map(|&(_, pos)| pos).collect();
+            let compressor = build_compressor(&ip_addrs_sorted, blanks);
+            if compressor.num_bits > MAX_NUM_BITS {
+                continue;
+            }
+            assert!(
+                num_bytes
+                    <= compressor
+                        .compress(&ip_addrs, &mut CompressionStats::default())
+                        .len()
+            );
+        }
+    }
 }
@@ -0,0 +1,149 @@
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    ops::RangeInclusive,
+};
+
+use crate::{decode_any, encode_best, IpReprError};
+
+/// Maps an ip address into the `u128` space used by the codecs.
+/// IPv4 addresses are mapped to IPv6 (`::ffff:a.b.c.d`).
+pub fn ip_addr_to_u128(ip_addr: IpAddr) -> u128 {
+    let ip_addr_v6: Ipv6Addr = match ip_addr {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    };
+    u128::from_be_bytes(ip_addr_v6.octets())
+}
+
+/// Inverse of `ip_addr_to_u128`: IPv4-mapped addresses are returned as IPv4.
+pub fn u128_to_ip_addr(ip_addr: u128) -> IpAddr {
+    let ip_addr_v6 = Ipv6Addr::from(ip_addr);
+    match ip_addr_v6.to_ipv4_mapped() {
+        Some(v4) => IpAddr::V4(v4),
+        None => IpAddr::V6(ip_addr_v6),
+    }
+}
+
+/// IPv4-mapped addresses are `::ffff:a.b.c.d`.
+pub(crate) const IPV4_MAPPED_PREFIX: u128 = 0xffff << 32;
+
+fn is_ipv4_mapped(ip_addr: u128) -> bool {
+    ip_addr >> 32 == 0xffff
+}
+
+/// Returns true if the column is not empty and all of its values are
+/// IPv4-mapped addresses.
+pub fn is_ipv4_only(ip_addrs: &[u128]) -> bool {
+    !ip_addrs.is_empty() && ip_addrs.iter().all(|&ip_addr| is_ipv4_mapped(ip_addr))
+}
+
+/// Maps IPv4-mapped addresses to the 32-bit IPv4 domain.
+///
+/// This is a change of domain only, done by the container: the codecs have no
+/// dedicated 32-bit path and their payload format is the same in both domains.
+/// Interval bases and dictionary entries are vints, and values are bitpacked in
+/// compact space, so they already take at most 32 bits in that domain. Zstd keeps
+/// 16 bytes per value, the zero high bytes of which it compresses away.
+pub fn to_ipv4_domain(ip_addrs: &[u128]) -> Vec<u128> {
+    debug_assert!(is_ipv4_only(ip_addrs));
+    ip_addrs
+        .iter()
+        .map(|&ip_addr| ip_addr - IPV4_MAPPED_PREFIX)
+        .collect()
+}
+
+/// Inverse of `to_ipv4_domain`, which fails if a value does not fit on 32 bits.
+pub(crate) fn from_ipv4_domain(ip_addrs: &mut [u128]) -> Result<(), IpReprError> {
+    for ip_addr in ip_addrs {
+        if *ip_addr > u32::MAX as u128 {
+            return Err(IpReprError::CorruptedData);
+        }
+        *ip_addr += IPV4_MAPPED_PREFIX;
+    }
+    Ok(())
+}
+
+/// Maps a range of ip addresses to the 32-bit IPv4 domain. Returns `None` if the
+/// range holds no IPv4-mapped address.
+pub(crate) fn range_to_ipv4_domain(range: RangeInclusive<u128>) -> Option<RangeInclusive<u128>> {
+    let lo = (*range.start()).max(IPV4_MAPPED_PREFIX);
+    let hi = (*range.end()).min(IPV4_MAPPED_PREFIX + u32::MAX as u128);
+    (lo <= hi).then(|| lo - IPV4_MAPPED_PREFIX..=hi - IPV4_MAPPED_PREFIX)
+}
+
+/// Encodes `ip_addrs` with the codec returned by `best_codec`. The column can be
+/// read back with `decode_ips`.
+pub fn encode_ips(ip_addrs: &[IpAddr]) -> Result<Vec<u8>, IpReprError> {
+    let ip_addrs: Vec<u128> = ip_addrs.iter().cloned().map(ip_addr_to_u128).collect();
+    encode_best(&ip_addrs)
+}
+
+/// Decodes a column written by `encode_ips`, or by `encode_container`.
+///
+/// IPv6 addresses in the IPv4-mapped range are returned as IPv4.
+pub fn decode_ips(data: &[u8]) -> Result<Vec<IpAddr>, IpReprError> {
+    let ip_addrs = decode_any(data)?;
+    Ok(ip_addrs.into_iter().map(u128_to_ip_addr).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_ip_addr_to_u128() {
+        let ip_addr = IpAddr::from_str("10.1.2.3").unwrap();
+        assert_eq!(ip_addr_to_u128(ip_addr), 0xffff_0a01_0203);
+        assert_eq!(u128_to_ip_addr(0xffff_0a01_0203), ip_addr);
+        let ip_addr = IpAddr::from_str("2001:db8::1").unwrap();
+        assert_eq!(u128_to_ip_addr(ip_addr_to_u128(ip_addr)), ip_addr);
+        assert_eq!(u128_to_ip_addr(0), IpAddr::from_str("::").unwrap());
+    }
+
+    #[test]
+    fn test_encode_decode_ips() {
+        let ip_addrs: Vec<IpAddr> = ["10.0.0.1", "192.168.1.1", "2001:db8::1", "::1", "10.0.0.1"]
+            .iter()
+            .map(|ip_addr| IpAddr::from_str(ip_addr).unwrap())
+            .collect();
+        let data = encode_ips(&ip_addrs).unwrap();
+        let decoded = decode_ips(&data).unwrap();
+        assert_eq!(decoded, ip_addrs);
+        assert!(decoded[0].is_ipv4());
+        assert!(decode_ips(&encode_ips(&[]).unwrap()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ipv4_domain() {
+        let mut ip_addrs = vec![0xffff_0000_0000, 0xffff_0a01_0203, 0xffff_ffff_ffff];
+        assert!(is_ipv4_only(&ip_addrs));
+        assert!(!is_ipv4_only(&[]));
+        assert!(!is_ipv4_only(&[0xffff_0a01_0203, 1]));
+        assert!(!is_ipv4_only(&[0x1_ffff_0a01_0203]));
+        let mut ipv4_domain = to_ipv4_domain(&ip_addrs);
+        assert_eq!(ipv4_domain, vec![0, 0x0a01_0203, 0xffff_ffff]);
+        from_ipv4_domain(&mut ipv4_domain).unwrap();
+        assert_eq!(ipv4_domain, ip_addrs);
+        ip_addrs[1] = 1 << 32;
+        assert_eq!(
+            from_ipv4_domain(&mut ip_addrs),
+            Err(IpReprError::CorruptedData)
+        );
+    }
+
+    #[test]
+    fn test_range_to_ipv4_domain() {
+        assert_eq!(
+            range_to_ipv4_domain(0xffff_0a00_0000..=0xffff_0aff_ffff),
+            Some(0x0a00_0000..=0x0aff_ffff)
+        );
+        assert_eq!(
+            range_to_ipv4_domain(0..=u128::MAX),
+            Some(0..=u32::MAX as u128)
+        );
+        assert_eq!(range_to_ipv4_domain(0..=0xfffe_ffff_ffff), None);
+        assert_eq!(range_to_ipv4_domain(0x1_0000_0000_0000..=u128::MAX), None);
+    }
+}
@@ -1,56 +1,123 @@
+mod bitset;
+mod cidr;
+mod codec_selection;
+mod column_writer;
+mod container;
+mod dictionary;
+mod elias_fano;
+mod error;
 mod half_dict;
 mod half_dict_quantile;
+mod heavy_hitters;
 mod interval;
+mod ip_addr;
+mod multivalue;
+mod nullable;
+mod run_length;
+mod stats;
+mod zstd_encoding;
 
+pub use bitset::BitSet;
+pub use cidr::Cidr;
+pub use codec_selection::{best_codec, encode_best};
+pub use column_writer::{IpColumnWriter, ValueCounts};
+pub use container::{decode_any, decode_chunked, encode_container, CodecId, ContainerHeader};
+pub use dictionary::{DictionaryEncoding, DictionaryReader};
+pub use elias_fano::{SortedEncoding, SortedReader};
+pub use error::IpReprError;
 use fnv::FnvHashMap;
 pub use half_dict::HalfDict;
 pub use half_dict_quantile::HalfDictQ;
-pub use interval::IntervalEncoding;
+pub use interval::{IntervalEncoding, IntervalReader};
+pub use ip_addr::{
+    decode_ips, encode_ips, ip_addr_to_u128, is_ipv4_only, to_ipv4_domain, u128_to_ip_addr,
+};
+pub use multivalue::{encode_multivalue, MultiValueReader};
+pub use nullable::{encode_nullable, NullableReader};
+pub use run_length::{RunLengthEncoding, RunLengthReader};
+pub use stats::{CompressionStats, EstimatedSize};
 use std::{collections::BinaryHeap, fmt::Debug};
+pub use zstd_encoding::ZstdEncoding;
 
 pub trait IpRepr: Debug {
-    fn encode(&self, ip_addrs: &[u128]) -> Vec<u8>;
-    fn decode(&self, ip_addrs: &[u8]) -> Vec<u128>;
+    /// Identifies the codec in the header written by `encode_container`.
+    fn codec_id(&self) -> CodecId;
+
+    /// Parameters required to recreate the codec when reading a container.
+    fn params(&self) -> Vec<u64>;
+
+    fn encode(&self, ip_addrs: &[u128]) -> Result<Vec<u8>, IpReprError> {
+        let (data, _stats) = self.encode_with_stats(ip_addrs)?;
+        Ok(data)
+    }
+
+    /// Encodes `ip_addrs` and reports how the encoded bytes are spent.
+    fn encode_with_stats(
+        &self,
+        ip_addrs: &[u128],
+    ) -> Result<(Vec<u8>, CompressionStats), IpReprError>;
+
+    /// Same as `encode_with_stats`, with the number of occurrences of each value
+    /// already known, as in `IpColumnWriter`. Codecs training on those counts use
+    /// them rather than computing them again.
+    fn encode_with_counts(
+        &self,
+        ip_addrs: &[u128],
+        _counts: &ValueCounts,
+    ) -> Result<(Vec<u8>, CompressionStats), IpReprError> {
+        self.encode_with_stats(ip_addrs)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u128>, IpReprError>;
+
+    /// Estimates the encoded size of `ip_addrs` by encoding a deterministic
+    /// sample of it. See `EstimatedSize` for the error bound.
+    fn estimate(&self, ip_addrs: &[u128]) -> Result<EstimatedSize, IpReprError> {
+        let sample = codec_selection::sample(ip_addrs);
+        let (_data, stats) = self.encode_with_stats(&sample)?;
+        Ok(EstimatedSize::extrapolate(&stats, ip_addrs.len()))
+    }
+
+    /// Returns the rows whose ip address is in any of `cidrs`.
+    fn matches_cidr(&self, data: &[u8], cidrs: &[Cidr]) -> Result<BitSet, IpReprError> {
+        let ip_addrs = self.decode(data)?;
+        let mut bitset = BitSet::with_max_value(ip_addrs.len() as u32);
+        for (row, &ip_addr) in ip_addrs.iter().enumerate() {
+            if cidrs.iter().any(|cidr| cidr.contains(ip_addr)) {
+                bitset.insert(row as u32);
+            }
+        }
+        Ok(bitset)
+    }
 }
 
-#[derive(Debug)]
+/// Ordered from the most to the least common, ties being broken by the smallest
+/// ip first, so that the dictionaries do not depend on the hash map iteration order.
+#[derive(Debug, PartialEq, Eq)]
 struct IPWithCount {
     ip: u128,
     count: usize,
 }
 
-impl PartialEq for IPWithCount {
-    fn eq(&self, other: &Self) -> bool {
-        self.count == other.count
-    }
-}
-impl Eq for IPWithCount {}
-
 impl PartialOrd for IPWithCount {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        other.count.partial_cmp(&self.count)
+        Some(self.cmp(other))
     }
 }
 impl Ord for IPWithCount {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        other.count.cmp(&self.count)
+        other
+            .count
+            .cmp(&self.count)
+            .then_with(|| self.ip.cmp(&other.ip))
     }
 }
 
-fn get_most_common(ip_addrs: &[u128], top_n: usize) -> Vec<IPWithCount> {
-    let cnts = ip_addrs.iter().fold(
-        FnvHashMap::<u128, usize>::with_capacity_and_hasher(
-            ip_addrs.len() / 10,
-            Default::default(),
-        ),
-        |mut acc, ip| {
-            let entry = acc.entry(*ip).or_default();
-            *entry += 1;
-            acc
-        },
-    );
-
-    let top_ips = cnts
+fn get_most_common(counts: &FnvHashMap<u128, usize>, top_n: usize) -> Vec<IPWithCount> {
+    if top_n == 0 {
+        return Vec::new();
+    }
+    let top_ips = counts
         .iter()
         .fold(BinaryHeap::<IPWithCount>::default(), |mut heap, entry| {
             let ip_with_count = IPWithCount {
@@ -69,6 +136,190 @@ fn get_most_common(ip_addrs: &[u128], top_n: usize) -> Vec<IPWithCount> {
 
             heap
         });
-    let top_ips_ordered = top_ips.into_sorted_vec();
-    top_ips_ordered
+    top_ips.into_sorted_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, net::IpAddr, process::Command, str::FromStr};
+
+    use super::*;
+
+    /// Deterministic pseudo random generator (splitmix64).
+    pub(crate) fn random_vals(num_vals: usize, mut seed: u64) -> impl Iterator<Item = u64> {
+        (0..num_vals).map(move |_| {
+            seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        })
+    }
+
+    /// Edge cases shared by the codecs with a random access reader.
+    pub(crate) fn edge_case_ip_addrs() -> Vec<Vec<u128>> {
+        vec![
+            vec![],
+            vec![1],
+            vec![u128::MAX, u128::MAX, 0, u128::MAX],
+            vec![7; 1000],
+            (0..1000u128).collect(),
+            (0..1000u128).rev().collect(),
+        ]
+    }
+
+    /// Checks that `codec` round-trips `ip_addrs`, and that `read_rows` returns
+    /// them, reading the encoded data one row at a time. Returns the encoded data.
+    pub(crate) fn check_random_access(
+        codec: &dyn IpRepr,
+        ip_addrs: &[u128],
+        read_rows: impl Fn(&[u8]) -> Vec<u128>,
+    ) -> Vec<u8> {
+        let data = codec.encode(ip_addrs).unwrap();
+        assert_eq!(&codec.decode(&data).unwrap(), ip_addrs);
+        assert_eq!(&read_rows(&data), ip_addrs);
+        data
+    }
+
+    /// Checks that `open_fails` holds for any truncation of `data` cutting into
+    /// more than its 7 trailing bytes of padding.
+    pub(crate) fn check_truncated(data: &[u8], open_fails: impl Fn(&[u8]) -> bool) {
+        for len in 1..data.len() - 7 {
+            assert!(open_fails(&data[..len]), "truncated to {len} bytes");
+        }
+    }
+
+    #[test]
+    fn test_most_common_ties() {
+        let counts: FnvHashMap<u128, usize> = [(5, 2), (3, 2), (9, 1), (1, 2), (7, 3)]
+            .into_iter()
+            .collect();
+        let top_ips: Vec<u128> = get_most_common(&counts, 3)
+            .iter()
+            .map(|entry| entry.ip)
+            .collect();
+        assert_eq!(top_ips, vec![7, 1, 3]);
+    }
+
+    const DUMP_ENCODED_ENV: &str = "IP_REPR_DUMP_ENCODED";
+
+    /// Encodes columns where many values have the same count.
+    fn encode_with_ties() -> Vec<String> {
+        let ip_addrs: Vec<u128> = random_vals(20_000, 7)
+            .map(|random| 0xffff_0a00_0000 + (random % 2000) as u128)
+            .collect();
+        let codecs: Vec<Box<dyn IpRepr>> = vec![
+            Box::new(HalfDict::new(1024, 6)),
+            Box::new(HalfDict::new(1024, 6).with_max_counters(128)),
+            Box::new(HalfDictQ::new(100)),
+            Box::new(HalfDictQ::new(100).with_max_counters(200)),
+        ];
+        codecs
+            .iter()
+            .map(|codec| {
+                let data = codec.encode(&ip_addrs).unwrap();
+                data.iter().map(|byte| format!("{byte:02x}")).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_encoding_is_deterministic_across_processes() {
+        let encoded = encode_with_ties();
+        if env::var_os(DUMP_ENCODED_ENV).is_some() {
+            for data in &encoded {
+                println!("encoded:{data}");
+            }
+            return;
+        }
+        assert_eq!(encoded, encode_with_ties());
+        let output = Command::new(env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "tests::test_encoding_is_deterministic_across_processes",
+                "--nocapture",
+            ])
+            .env(DUMP_ENCODED_ENV, "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let encoded_in_child: Vec<String> = stdout
+            .lines()
+            // The first line is prefixed by the name of the test.
+            .filter_map(|line| Some(line.split_once("encoded:")?.1))
+            .map(String::from)
+            .collect();
+        assert_eq!(encoded_in_child, encoded);
+    }
+
+    #[test]
+    fn test_matches_cidr_all_codecs() {
+        let ip_addrs: Vec<u128> = [
+            "10.0.0.1",
+            "10.255.3.4",
+            "11.0.0.1",
+            "192.168.1.1",
+            "2001:db8::1",
+            "2001:db9::1",
+            "10.0.0.1",
+            "::1",
+        ]
+        .iter()
+        .map(|ip_addr| ip_addr_to_u128(IpAddr::from_str(ip_addr).unwrap()))
+        .collect();
+        let cidrs = [
+            Cidr::from_str("10.0.0.0/8").unwrap(),
+            Cidr::from_str("2001:db8::/32").unwrap(),
+            Cidr::from_str("10.0.0.0/24").unwrap(),
+        ];
+        let codecs: Vec<Box<dyn IpRepr>> = vec![
+            Box::new(IntervalEncoding::default()),
+            Box::new(HalfDict::new(4, 1)),
+            Box::new(HalfDictQ::new(2)),
+            Box::new(RunLengthEncoding::default()),
+            Box::new(DictionaryEncoding::default()),
+        ];
+        for codec in codecs {
+            let data = codec.encode(&ip_addrs).unwrap();
+            let bitset = codec.matches_cidr(&data, &cidrs).unwrap();
+            assert_eq!(bitset.max_value(), 8);
+            assert_eq!(
+                bitset.iter().collect::<Vec<_>>(),
+                vec![0, 1, 4, 6],
+                "{codec:?}"
+            );
+            let bitset = codec.matches_cidr(&data, &[]).unwrap();
+            assert!(bitset.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_compression_stats() {
+        let ip_addrs: Vec<u128> = (0..5000u128)
+            .map(|i| 0xffff_0a00_0000 + (i * i) % 3000)
+            .collect();
+        let codecs: Vec<Box<dyn IpRepr>> = vec![
+            Box::new(IntervalEncoding::default()),
+            Box::new(HalfDict::new(1024, 4)),
+            Box::new(HalfDictQ::new(16)),
+            Box::new(RunLengthEncoding::default()),
+            Box::new(DictionaryEncoding::default()),
+        ];
+        for codec in codecs {
+            let (data, stats) = codec.encode_with_stats(&ip_addrs).unwrap();
+            assert_eq!(stats.num_vals, ip_addrs.len());
+            assert_eq!(stats.num_bytes(), data.len(), "{codec:?}");
+            assert!(stats.header_num_bytes > 0);
+            assert!(stats.bits_per_element() < 128.0);
+        }
+        let (data, stats) = IntervalEncoding::default()
+            .encode_with_stats(&ip_addrs)
+            .unwrap();
+        assert_eq!(data, IntervalEncoding::default().encode(&ip_addrs).unwrap());
+        assert!(stats.num_intervals.is_some());
+        assert!(stats.num_bits.unwrap() <= 12);
+        let (_, stats) = HalfDictQ::new(16).encode_with_stats(&ip_addrs).unwrap();
+        assert_eq!(stats.dict_size, Some(16));
+    }
 }
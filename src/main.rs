@@ -1,13 +1,15 @@
-use ip_repr::{HalfDict, HalfDictQ, IntervalEncoding, IpRepr};
+use ip_repr::{
+    best_codec, ip_addr_to_u128, is_ipv4_only, to_ipv4_domain, CompressionStats,
+    DictionaryEncoding, HalfDict, HalfDictQ, IntervalEncoding, IpRepr, RunLengthEncoding,
+    ZstdEncoding,
+};
 use itertools::Itertools;
 use std::{
     collections::HashSet,
     io::{self, BufRead},
-    net::{IpAddr, Ipv6Addr},
+    net::IpAddr,
     str::FromStr,
-    time::Instant,
 };
-use structopt::StructOpt;
 
 // #[derive(Debug, StructOpt)]
 // struct Opt {
@@ -24,9 +26,11 @@ enum Compressor {
     Interval,
     HalfDict,
     HalfDictQuantil,
+    RunLength,
+    Dictionary,
 }
 
-const ALL_COMPRESSORS: [Compressor; 4] = [Compressor::Zstd, Compressor::HalfDict, Compressor::HalfDictQuantil, Compressor::Interval];
+const ALL_COMPRESSORS: [Compressor; 6] = [Compressor::Zstd, Compressor::HalfDict, Compressor::HalfDictQuantil, Compressor::Interval, Compressor::RunLength, Compressor::Dictionary];
 
 impl FromStr for Compressor {
     type Err = String;
@@ -36,6 +40,8 @@ impl FromStr for Compressor {
             "interval" => Ok(Compressor::Interval),
             "halfdict" => Ok(Compressor::HalfDict),
             "halfdict_quantil" => Ok(Compressor::HalfDictQuantil),
+            "run_length" => Ok(Compressor::RunLength),
+            "dictionary" => Ok(Compressor::Dictionary),
             _ => Err("Could not parse the compression type".to_string()),
         }
     }
@@ -55,17 +61,14 @@ fn ip_dataset(print_stats: bool) -> Vec<u128> {
             if ip_addr.is_ipv4() {
                 ip_addr_v4 += 1;
             }
-            let ip_addr_v6: Ipv6Addr = match ip_addr {
-                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
-                IpAddr::V6(v6) => v6,
-            };
-            Some(ip_addr_v6)
+            Some(ip_addr_to_u128(ip_addr))
         })
-        .map(|ip_v6| u128::from_be_bytes(ip_v6.octets()))
         .collect();
 
-    println!("IpAddrsAny\t{}", ip_addrs.len());
-    println!("IpAddrsV4\t{}", ip_addr_v4);
+    if print_stats {
+        println!("IpAddrsAny\t{}", ip_addrs.len());
+        println!("IpAddrsV4\t{}", ip_addr_v4);
+    }
 
     ip_addrs
 }
@@ -114,6 +117,48 @@ fn print_set_stats(ip_addrs: &[u128]) {
 
 }
 
+fn print_compression_stats(stats: &CompressionStats) {
+    if let Some(num_intervals) = stats.num_intervals {
+        println!("NumIntervals\t{}", num_intervals);
+    }
+    if let Some(num_bits) = stats.num_bits {
+        println!("NumBits\t{}", num_bits);
+    }
+    if let Some(dict_size) = stats.dict_size {
+        println!("DictSize\t{}", dict_size);
+    }
+    if let Some(dict_num_bits) = stats.dict_num_bits {
+        println!("DictNumBits\t{}", dict_num_bits);
+    }
+    if let Some(num_runs) = stats.num_runs {
+        println!("NumRuns\t{}", num_runs);
+    }
+    println!("HeaderLen\t{}", stats.header_num_bytes);
+    println!("PayloadLen\t{}", stats.payload_num_bytes);
+    println!("num_bytes\t{}", stats.num_bytes());
+    println!("bits_per_el\t{:.2}", stats.bits_per_element());
+    println!("Compression: {:.2}%", 100.0 * stats.compression_ratio());
+    println!("Train Time: {}ms", stats.train_time.as_millis());
+    println!("Compress Time: {}ms", stats.compress_time.as_millis());
+}
+
+fn encode_and_check(encoder: &dyn IpRepr, ip_addrs: &[u128]) {
+    let (encoded, stats) = encoder.encode_with_stats(ip_addrs).unwrap();
+    let decoded = encoder.decode(&encoded).unwrap();
+    assert_eq!(&decoded, ip_addrs);
+    print_compression_stats(&stats);
+    if is_ipv4_only(ip_addrs) {
+        let ipv4_domain = to_ipv4_domain(ip_addrs);
+        let encoded_ipv4 = encoder.encode(&ipv4_domain).unwrap();
+        assert_eq!(encoder.decode(&encoded_ipv4).unwrap(), ipv4_domain);
+        println!("IPv4 num_bytes\t{}", encoded_ipv4.len());
+        println!(
+            "IPv4 Saving: {:.2}%",
+            100.0 * (1.0 - encoded_ipv4.len() as f64 / encoded.len() as f64)
+        );
+    }
+}
+
 fn main() {
     // let args = Opt::from_args();
     let ip_addrs = ip_dataset(true);
@@ -122,6 +167,8 @@ fn main() {
     print_set_stats(&ip_addrs);
     // }
 
+    println!("Best codec: {:?}", best_codec(&ip_addrs));
+
     for compressor in ALL_COMPRESSORS {
         println!("\n\r=====================\nCOMPRESSOR {compressor:?}");
         match compressor {
@@ -135,47 +182,25 @@ fn main() {
                 for encoder in encoders {
                     println!("\n\n-----");
                     println!("{:?}", encoder);
-                    let encoded = encoder.encode(&ip_addrs);
-                    let decoded = encoder.decode(&encoded);
-                    assert_eq!(&decoded, &ip_addrs);
-                    let num_bytes = encoded.len();
-                    println!("num_bytes\t{num_bytes:.2}");
-                    let bits_per_el = (8 * num_bytes) as f64 / ip_addrs.len() as f64;
-                    println!("bits_per_el\t{:.2}", bits_per_el);
+                    encode_and_check(&*encoder, &ip_addrs);
                 }
             }
             Compressor::HalfDict => {
-                let half_dict = HalfDict::new(1024, 8);
-                half_dict.encode(&ip_addrs);
+                let half_dict = HalfDict::new(1024, 16).with_adaptive_dict_size(true);
+                encode_and_check(&half_dict, &ip_addrs);
             }
             Compressor::HalfDictQuantil => {
-                let half_dict = HalfDictQ::new(4096 * 2);
-                half_dict.encode(&ip_addrs);
-
-                let half_dict = HalfDictQ::new(4096);
-                half_dict.encode(&ip_addrs);
-
-                let half_dict = HalfDictQ::new(4096 / 2);
-                half_dict.encode(&ip_addrs);
-
-                let half_dict = HalfDictQ::new(4096 / 4);
-                half_dict.encode(&ip_addrs);
+                let half_dict = HalfDictQ::new(1 << 16).with_adaptive_dict_size(true);
+                encode_and_check(&half_dict, &ip_addrs);
             }
             Compressor::Zstd => {
-                let bytes: Vec<u8> = ip_addrs.iter().fold(vec![], |mut acc, el| {
-                    acc.extend_from_slice(&el.to_le_bytes());
-                    acc
-                });
-
-                let start = Instant::now();
-                let mut out = vec![];
-                zstd::stream::copy_encode(&*bytes, &mut out, 3).unwrap();
-                println!("Compress Time: {}ms", (Instant::now() - start).as_millis());
-                println!("Compressed len: {}", out.len());
-                println!(
-                    "Compression: {:.2}%",
-                    100.0 * out.len() as f64 / (ip_addrs.len() as f64 * 16.0)
-                );
+                encode_and_check(&ZstdEncoding::default(), &ip_addrs);
+            }
+            Compressor::RunLength => {
+                encode_and_check(&RunLengthEncoding::default(), &ip_addrs);
+            }
+            Compressor::Dictionary => {
+                encode_and_check(&DictionaryEncoding::default(), &ip_addrs);
             }
         }
     }
@@ -0,0 +1,329 @@
+use std::ops::RangeInclusive;
+
+use tantivy_bitpacker::{compute_num_bits, BitPacker, BitUnpacker};
+
+use crate::{
+    decode_any, encode_container,
+    interval::{check_bitpacked_len, deserialize_num_vals, serialize_vint},
+    ip_addr::{range_to_ipv4_domain, IPV4_MAPPED_PREFIX},
+    CodecId, ContainerHeader, IntervalReader, IpRepr, IpReprError,
+};
+
+/// Number of bytes written by `BitPacker::close` for `num_vals` values.
+fn bitpacked_num_bytes(num_vals: usize, num_bits: u8) -> usize {
+    (num_vals * num_bits as usize).div_ceil(8) + 7
+}
+
+/// Encodes a column holding any number of ip addresses per document.
+///
+/// The values of all of the documents are concatenated and encoded with `codec`.
+/// The documents are mapped to their values by offsets into those values.
+///
+/// Layout:
+/// - number of documents
+/// - number of bits per offset
+/// - the `num_docs + 1` offsets, bitpacked
+/// - the container of the values, see `encode_container`
+pub fn encode_multivalue(
+    codec: &dyn IpRepr,
+    vals_per_doc: &[Vec<u128>],
+) -> Result<Vec<u8>, IpReprError> {
+    let num_docs = u32::try_from(vals_per_doc.len()).map_err(|_| IpReprError::InvalidNumVals)?;
+    let ip_addrs: Vec<u128> = vals_per_doc.iter().flatten().cloned().collect();
+    let num_vals = u32::try_from(ip_addrs.len()).map_err(|_| IpReprError::InvalidNumVals)?;
+
+    let mut output = Vec::new();
+    serialize_vint(num_docs as u128, &mut output);
+    let num_bits = compute_num_bits(num_vals as u64);
+    output.push(num_bits);
+    let mut bitpacker = BitPacker::default();
+    let mut offset = 0u64;
+    bitpacker.write(offset, num_bits, &mut output).unwrap();
+    for vals in vals_per_doc {
+        offset += vals.len() as u64;
+        bitpacker.write(offset, num_bits, &mut output).unwrap();
+    }
+    bitpacker.close(&mut output).unwrap();
+    output.extend_from_slice(&encode_container(codec, &ip_addrs, false)?);
+    Ok(output)
+}
+
+/// Flattened values of a multivalue column.
+enum Values<'a> {
+    /// Values encoded with `IntervalEncoding`, read in place. Range queries are
+    /// evaluated in compact space.
+    Interval {
+        reader: IntervalReader<'a>,
+        ipv4_only: bool,
+    },
+    /// Values of the other codecs, decoded when opening the column.
+    Decoded(Vec<u128>),
+}
+
+impl Values<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Values::Interval { reader, .. } => reader.len() as usize,
+            Values::Decoded(ip_addrs) => ip_addrs.len(),
+        }
+    }
+
+    fn get(&self, idx: usize) -> u128 {
+        match self {
+            Values::Interval { reader, ipv4_only } => {
+                let ip_addr = reader.get(idx as u32);
+                if *ipv4_only {
+                    ip_addr + IPV4_MAPPED_PREFIX
+                } else {
+                    ip_addr
+                }
+            }
+            Values::Decoded(ip_addrs) => ip_addrs[idx],
+        }
+    }
+
+    /// Returns the indexes of the values within `range`, in increasing order.
+    fn get_val_ids_for_value_range(&self, range: RangeInclusive<u128>) -> Vec<u32> {
+        match self {
+            Values::Interval { reader, ipv4_only } => {
+                let range = if *ipv4_only {
+                    range_to_ipv4_domain(range)
+                } else {
+                    Some(range)
+                };
+                range
+                    .map(|range| reader.get_row_ids_for_value_range(range))
+                    .unwrap_or_default()
+            }
+            Values::Decoded(ip_addrs) => ip_addrs
+                .iter()
+                .enumerate()
+                .filter(|(_, ip_addr)| range.contains(ip_addr))
+                .map(|(idx, _)| idx as u32)
+                .collect(),
+        }
+    }
+}
+
+/// Reader over a column written by `encode_multivalue`.
+///
+/// Values encoded with `IntervalEncoding` are read in place; those of the other
+/// codecs are decoded by `open`.
+pub struct MultiValueReader<'a> {
+    num_docs: u32,
+    offsets_unpacker: BitUnpacker,
+    offsets_data: &'a [u8],
+    values: Values<'a>,
+}
+
+impl<'a> MultiValueReader<'a> {
+    pub fn open(data: &'a [u8]) -> Result<MultiValueReader<'a>, IpReprError> {
+        let (num_docs, data) = deserialize_num_vals(data)?;
+        let (&num_bits, data) = data.split_first().ok_or(IpReprError::TruncatedHeader)?;
+        if num_bits > 32 {
+            return Err(IpReprError::InvalidNumBits(num_bits));
+        }
+        let offsets_num_bytes = bitpacked_num_bytes(num_docs + 1, num_bits);
+        if offsets_num_bytes > data.len() {
+            return Err(IpReprError::TruncatedData);
+        }
+        let (offsets_data, data) = data.split_at(offsets_num_bytes);
+        check_bitpacked_len(num_docs + 1, num_bits, offsets_data)?;
+        let (header, payload) = ContainerHeader::open(data)?;
+        let values = if header.codec_id == CodecId::Interval {
+            let reader = IntervalReader::open(payload)?;
+            if reader.len() as usize != header.num_vals {
+                return Err(IpReprError::CorruptedData);
+            }
+            // Checked once here, so that `Values::get` can map them back without overflowing.
+            if header.ipv4_only && reader.iter().any(|ip_addr| ip_addr > u32::MAX as u128) {
+                return Err(IpReprError::CorruptedData);
+            }
+            Values::Interval {
+                reader,
+                ipv4_only: header.ipv4_only,
+            }
+        } else {
+            Values::Decoded(decode_any(data)?)
+        };
+        let reader = MultiValueReader {
+            num_docs: num_docs as u32,
+            offsets_unpacker: BitUnpacker::new(num_bits),
+            offsets_data,
+            values,
+        };
+        let mut prev_offset = 0;
+        for doc in 0..=reader.num_docs {
+            let offset = reader.offset(doc);
+            if offset < prev_offset {
+                return Err(IpReprError::CorruptedData);
+            }
+            prev_offset = offset;
+        }
+        if prev_offset != reader.values.len() {
+            return Err(IpReprError::CorruptedData);
+        }
+        Ok(reader)
+    }
+
+    fn offset(&self, doc: u32) -> usize {
+        self.offsets_unpacker.get(doc as u64, self.offsets_data) as usize
+    }
+
+    pub fn num_docs(&self) -> u32 {
+        self.num_docs
+    }
+
+    /// Returns the total number of values, over all of the documents.
+    pub fn num_vals(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns the document holding the value at `idx`.
+    fn doc_for_val(&self, idx: usize) -> u32 {
+        // First document whose values end after `idx`, empty ones being skipped.
+        let (mut lo, mut hi) = (0, self.num_docs);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.offset(mid + 1) <= idx {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    /// Returns the values of `doc`, in the order they were written.
+    ///
+    /// Panics if `doc` is out of bounds.
+    pub fn get_vals(&self, doc: u32) -> impl Iterator<Item = u128> + '_ {
+        assert!(doc < self.num_docs, "doc {doc} out of bounds");
+        (self.offset(doc)..self.offset(doc + 1)).map(move |idx| self.values.get(idx))
+    }
+
+    /// Returns the documents having at least one value in `range`, sorted.
+    ///
+    /// The matching values are found by the codec, see
+    /// `IntervalReader::get_row_ids_for_value_range`, and then mapped to their
+    /// documents through the offsets.
+    pub fn get_docs_for_value_range(&self, range: RangeInclusive<u128>) -> Vec<u32> {
+        let mut doc_ids = Vec::new();
+        let mut doc_end = 0;
+        for idx in self.values.get_val_ids_for_value_range(range) {
+            let idx = idx as usize;
+            // Same document as the previous match.
+            if idx < doc_end {
+                continue;
+            }
+            let doc = self.doc_for_val(idx);
+            doc_ids.push(doc);
+            doc_end = self.offset(doc + 1);
+        }
+        doc_ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HalfDict, IntervalEncoding};
+
+    fn vals_per_doc() -> Vec<Vec<u128>> {
+        (0..500u128)
+            .map(|doc| {
+                (0..doc % 4)
+                    .map(|i| 0xffff_0a00_0000 + doc * 3 + i)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_multivalue() {
+        let vals_per_doc = vals_per_doc();
+        for codec in [
+            &IntervalEncoding::default() as &dyn IpRepr,
+            &HalfDict::new(64, 3),
+        ] {
+            let data = encode_multivalue(codec, &vals_per_doc).unwrap();
+            let reader = MultiValueReader::open(&data).unwrap();
+            assert_eq!(reader.num_docs(), 500);
+            assert_eq!(
+                reader.num_vals(),
+                vals_per_doc.iter().map(Vec::len).sum::<usize>()
+            );
+            for (doc, vals) in vals_per_doc.iter().enumerate() {
+                assert_eq!(&reader.get_vals(doc as u32).collect::<Vec<_>>(), vals);
+            }
+        }
+    }
+
+    #[test]
+    fn test_multivalue_empty() {
+        let data = encode_multivalue(&IntervalEncoding::default(), &[]).unwrap();
+        let reader = MultiValueReader::open(&data).unwrap();
+        assert_eq!(reader.num_docs(), 0);
+        let data = encode_multivalue(&IntervalEncoding::default(), &[vec![], vec![]]).unwrap();
+        let reader = MultiValueReader::open(&data).unwrap();
+        assert_eq!(reader.num_docs(), 2);
+        assert_eq!(reader.get_vals(1).count(), 0);
+        assert!(reader.get_docs_for_value_range(0..=u128::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_get_docs_for_value_range() {
+        let ipv4_vals_per_doc = vals_per_doc();
+        // Not IPv4 only, so not encoded in the IPv4 domain.
+        let mut mixed_vals_per_doc = vals_per_doc();
+        mixed_vals_per_doc[7].push(1 << 100);
+        for vals_per_doc in [ipv4_vals_per_doc, mixed_vals_per_doc] {
+            for codec in [
+                &IntervalEncoding::default() as &dyn IpRepr,
+                &HalfDict::new(64, 3),
+            ] {
+                let data = encode_multivalue(codec, &vals_per_doc).unwrap();
+                let reader = MultiValueReader::open(&data).unwrap();
+                for range in [
+                    0xffff_0a00_0000..=0xffff_0a00_0010,
+                    0xffff_0a00_0100..=0xffff_0a00_0100,
+                    0xffff_0a00_0200..=u128::MAX,
+                    0..=u128::MAX,
+                    0..=1,
+                ] {
+                    let expected: Vec<u32> = vals_per_doc
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, vals)| vals.iter().any(|val| range.contains(val)))
+                        .map(|(doc, _)| doc as u32)
+                        .collect();
+                    assert_eq!(reader.get_docs_for_value_range(range), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_multivalue_truncated() {
+        let data = encode_multivalue(&IntervalEncoding::default(), &vals_per_doc()).unwrap();
+        for len in 0..data.len() {
+            assert!(MultiValueReader::open(&data[..len]).is_err());
+        }
+    }
+    #[test]
+    fn test_multivalue_ipv4_value_out_of_range() {
+        let ip_addrs = [1 << 100];
+        let mut data =
+            encode_multivalue(&IntervalEncoding::default(), &[ip_addrs.to_vec()]).unwrap();
+        assert!(MultiValueReader::open(&data).is_ok());
+        // Sets the IPv4 only bit of the container flags, after the magic bytes, the
+        // version and the codec id. There is no checksum to notice it.
+        let container = encode_container(&IntervalEncoding::default(), &ip_addrs, false).unwrap();
+        let flags_pos = data.len() - container.len() + 6;
+        data[flags_pos] |= 2;
+        assert!(matches!(
+            MultiValueReader::open(&data),
+            Err(IpReprError::CorruptedData)
+        ));
+    }
+}
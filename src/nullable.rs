@@ -0,0 +1,233 @@
+use crate::{
+    decode_any, encode_container,
+    interval::{
+        deserialize_num_vals, deserialize_sorted_ips, serialize_sorted_ips, serialize_vint,
+    },
+    BitSet, IpRepr, IpReprError,
+};
+
+const DENSE_PRESENCE: u8 = 0;
+const SPARSE_PRESENCE: u8 = 1;
+
+/// Rows holding a value.
+#[derive(Debug)]
+enum Presence {
+    /// One bit per row, with the number of set bits before each word.
+    Dense {
+        bitset: BitSet,
+        word_ranks: Vec<u32>,
+    },
+    /// Sorted row ids.
+    Sparse(Vec<u32>),
+}
+
+impl Presence {
+    /// Returns the index of the value of `row` among the non-null values.
+    fn rank(&self, row: u32) -> Option<usize> {
+        match self {
+            Presence::Dense { bitset, word_ranks } => {
+                if !bitset.contains(row) {
+                    return None;
+                }
+                let word = bitset.words()[row as usize / 64];
+                let bits_before = word & ((1u64 << (row % 64)) - 1);
+                Some(word_ranks[row as usize / 64] as usize + bits_before.count_ones() as usize)
+            }
+            Presence::Sparse(row_ids) => row_ids.binary_search(&row).ok(),
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = u32> + '_> {
+        match self {
+            Presence::Dense { bitset, .. } => Box::new(bitset.iter()),
+            Presence::Sparse(row_ids) => Box::new(row_ids.iter().cloned()),
+        }
+    }
+}
+
+/// Encodes a column where some rows have no ip address.
+///
+/// The non-null values are encoded with `codec`, and the rows holding them with
+/// either a bitset or a sorted list of row ids, whichever is smaller.
+///
+/// Layout:
+/// - number of rows
+/// - presence kind: 0 for a bitset, 1 for a list of row ids
+/// - the bitset words, or the delta encoded row ids
+/// - the container of the non-null values, see `encode_container`
+pub fn encode_nullable(
+    codec: &dyn IpRepr,
+    ip_addrs: &[Option<u128>],
+) -> Result<Vec<u8>, IpReprError> {
+    let num_rows = u32::try_from(ip_addrs.len()).map_err(|_| IpReprError::InvalidNumVals)?;
+    let mut bitset = BitSet::with_max_value(num_rows);
+    let mut non_null_ip_addrs = Vec::new();
+    for (row, ip_addr_opt) in ip_addrs.iter().enumerate() {
+        if let Some(ip_addr) = ip_addr_opt {
+            bitset.insert(row as u32);
+            non_null_ip_addrs.push(*ip_addr);
+        }
+    }
+
+    let mut output = Vec::new();
+    serialize_vint(num_rows as u128, &mut output);
+    let mut row_ids = Vec::new();
+    let row_ids_sorted: Vec<u128> = bitset.iter().map(|row| row as u128).collect();
+    serialize_sorted_ips(&row_ids_sorted, &mut row_ids);
+    if row_ids.len() < bitset.words().len() * 8 {
+        output.push(SPARSE_PRESENCE);
+        output.extend_from_slice(&row_ids);
+    } else {
+        output.push(DENSE_PRESENCE);
+        bitset.serialize(&mut output);
+    }
+    output.extend_from_slice(&encode_container(codec, &non_null_ip_addrs, false)?);
+    Ok(output)
+}
+
+/// Reader over a column written by `encode_nullable`.
+#[derive(Debug)]
+pub struct NullableReader {
+    num_rows: u32,
+    presence: Presence,
+    ip_addrs: Vec<u128>,
+}
+
+impl NullableReader {
+    pub fn open(data: &[u8]) -> Result<NullableReader, IpReprError> {
+        let (num_rows, data) = deserialize_num_vals(data)?;
+        let num_rows = num_rows as u32;
+        let (&presence_kind, data) = data.split_first().ok_or(IpReprError::TruncatedHeader)?;
+        let (presence, data) = match presence_kind {
+            DENSE_PRESENCE => {
+                let (bitset, data) = BitSet::deserialize(num_rows, data)?;
+                let mut word_ranks = Vec::with_capacity(bitset.words().len());
+                let mut rank = 0u32;
+                for word in bitset.words() {
+                    word_ranks.push(rank);
+                    rank += word.count_ones();
+                }
+                (Presence::Dense { bitset, word_ranks }, data)
+            }
+            SPARSE_PRESENCE => {
+                let (row_ids, data) = deserialize_sorted_ips(data)?;
+                if row_ids.windows(2).any(|rows| rows[0] >= rows[1])
+                    || row_ids.last().is_some_and(|&row| row >= num_rows as u128)
+                {
+                    return Err(IpReprError::CorruptedData);
+                }
+                let row_ids = row_ids.into_iter().map(|row| row as u32).collect();
+                (Presence::Sparse(row_ids), data)
+            }
+            _ => return Err(IpReprError::CorruptedData),
+        };
+        let ip_addrs = decode_any(data)?;
+        if ip_addrs.len() != presence.iter().count() {
+            return Err(IpReprError::CorruptedData);
+        }
+        Ok(NullableReader {
+            num_rows,
+            presence,
+            ip_addrs,
+        })
+    }
+
+    pub fn num_rows(&self) -> u32 {
+        self.num_rows
+    }
+
+    /// Returns the number of rows holding a value.
+    pub fn num_non_null(&self) -> usize {
+        self.ip_addrs.len()
+    }
+
+    /// Returns the value of `row`, or `None` if the row has no value or is out
+    /// of bounds.
+    pub fn get(&self, row: u32) -> Option<u128> {
+        if row >= self.num_rows {
+            return None;
+        }
+        self.presence.rank(row).map(|idx| self.ip_addrs[idx])
+    }
+
+    /// Iterates over the rows holding a value, with their value.
+    pub fn iter_non_null(&self) -> impl Iterator<Item = (u32, u128)> + '_ {
+        self.presence.iter().zip(self.ip_addrs.iter().cloned())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Option<u128>> + '_ {
+        (0..self.num_rows).map(move |row| self.get(row))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HalfDict, IntervalEncoding};
+
+    fn test_aux_vals(codec: &dyn IpRepr, ip_addrs: &[Option<u128>]) -> Vec<u8> {
+        let data = encode_nullable(codec, ip_addrs).unwrap();
+        let reader = NullableReader::open(&data).unwrap();
+        assert_eq!(reader.num_rows() as usize, ip_addrs.len());
+        assert_eq!(reader.iter().collect::<Vec<_>>(), ip_addrs);
+        let non_null: Vec<(u32, u128)> = ip_addrs
+            .iter()
+            .enumerate()
+            .filter_map(|(row, ip_addr)| Some((row as u32, (*ip_addr)?)))
+            .collect();
+        assert_eq!(reader.iter_non_null().collect::<Vec<_>>(), non_null);
+        assert_eq!(reader.num_non_null(), non_null.len());
+        assert_eq!(reader.get(ip_addrs.len() as u32), None);
+        data
+    }
+
+    #[test]
+    fn test_nullable_dense() {
+        let ip_addrs: Vec<Option<u128>> = (0..1000u128)
+            .map(|i| (i % 3 != 0).then_some(0xffff_0a00_0000 + i % 100))
+            .collect();
+        let data = test_aux_vals(&IntervalEncoding::default(), &ip_addrs);
+        assert_eq!(data[2], DENSE_PRESENCE);
+        test_aux_vals(&HalfDict::new(128, 4), &ip_addrs);
+    }
+
+    #[test]
+    fn test_nullable_sparse() {
+        let ip_addrs: Vec<Option<u128>> = (0..10_000u128)
+            .map(|i| (i % 1000 == 7).then_some(1 << 100 | i))
+            .collect();
+        let data = test_aux_vals(&IntervalEncoding::default(), &ip_addrs);
+        assert_eq!(data[2], SPARSE_PRESENCE);
+    }
+
+    #[test]
+    fn test_nullable_edge_cases() {
+        test_aux_vals(&IntervalEncoding::default(), &[]);
+        test_aux_vals(&IntervalEncoding::default(), &[None, None]);
+        test_aux_vals(&IntervalEncoding::default(), &[Some(u128::MAX), Some(0)]);
+    }
+
+    #[test]
+    fn test_nullable_corrupted() {
+        let ip_addrs: Vec<Option<u128>> = (0..100u128).map(|i| (i % 2 == 0).then_some(i)).collect();
+        let data = encode_nullable(&IntervalEncoding::default(), &ip_addrs).unwrap();
+        for len in 0..data.len() {
+            assert!(NullableReader::open(&data[..len]).is_err());
+        }
+    }
+    #[test]
+    fn test_nullable_invalid_row_ids() {
+        let container = encode_container(&IntervalEncoding::default(), &[1, 2], false).unwrap();
+        for row_ids in [[1, 1], [1, 3]] {
+            let mut data = Vec::new();
+            serialize_vint(3, &mut data);
+            data.push(SPARSE_PRESENCE);
+            serialize_sorted_ips(&row_ids, &mut data);
+            data.extend_from_slice(&container);
+            assert_eq!(
+                NullableReader::open(&data).unwrap_err(),
+                IpReprError::CorruptedData
+            );
+        }
+    }
+}
@@ -0,0 +1,389 @@
+use std::{net::IpAddr, time::Instant};
+
+use tantivy_bitpacker::{compute_num_bits, BitPacker, BitUnpacker};
+
+use crate::{
+    interval::{
+        check_bitpacked_len, deserialize_num_vals, serialize_vint, train, IntervalCompressor,
+        IntervallDecompressor,
+    },
+    u128_to_ip_addr, BitSet, Cidr, CodecId, CompressionStats, IpRepr, IpReprError,
+};
+
+/// Encodes the runs of repeated ip addresses, as found in access logs where a
+/// session emits many requests in a row.
+///
+/// The value of each run is bitpacked in the compact space of an
+/// `IntervalCompressor`, and the row at which it starts is bitpacked next to it,
+/// so that rows are found by binary search without decoding the runs. The
+/// parameter is the one of `IntervalEncoding`.
+#[derive(Default, Debug)]
+pub struct RunLengthEncoding(pub usize);
+
+/// Splits `ip_addrs` into runs of the same value, as `(value, run length)`.
+fn runs(ip_addrs: &[u128]) -> Vec<(u128, usize)> {
+    let mut runs: Vec<(u128, usize)> = Vec::new();
+    for &ip_addr in ip_addrs {
+        match runs.last_mut() {
+            Some((run_ip_addr, run_len)) if *run_ip_addr == ip_addr => *run_len += 1,
+            _ => runs.push((ip_addr, 1)),
+        }
+    }
+    runs
+}
+
+/// Layout:
+/// - the header of the `IntervalCompressor`
+/// - the number of values and the number of runs
+/// - the number of bits per run start
+/// - the values of the runs, bitpacked in compact space
+/// - the rows at which the runs start, bitpacked
+/// - 7 bytes of padding, for the `BitUnpacker`.
+fn compress(
+    interval_compressor: &IntervalCompressor,
+    num_vals: usize,
+    runs: &[(u128, usize)],
+    stats: &mut CompressionStats,
+) -> Vec<u8> {
+    let mut output = Vec::new();
+    interval_compressor.write_header(&mut output);
+    serialize_vint(num_vals as u128, &mut output);
+    serialize_vint(runs.len() as u128, &mut output);
+    let num_bits_run_start = compute_num_bits(num_vals as u64 - 1);
+    output.push(num_bits_run_start);
+    stats.num_vals = num_vals;
+    stats.header_num_bytes = output.len();
+    stats.num_intervals = Some(interval_compressor.num_intervals());
+    stats.num_bits = Some(interval_compressor.num_bits);
+    stats.num_runs = Some(runs.len());
+
+    let mut bitpacker = BitPacker::default();
+    for &(ip_addr, _) in runs {
+        let compact = interval_compressor.to_compact(ip_addr);
+        bitpacker
+            .write(compact, interval_compressor.num_bits, &mut output)
+            .unwrap();
+    }
+    bitpacker.flush(&mut output).unwrap();
+    let mut run_start = 0;
+    for &(_, run_len) in runs {
+        bitpacker
+            .write(run_start, num_bits_run_start, &mut output)
+            .unwrap();
+        run_start += run_len as u64;
+    }
+    bitpacker.close(&mut output).unwrap();
+    stats.payload_num_bytes = output.len() - stats.header_num_bytes;
+    output
+}
+
+impl RunLengthEncoding {
+    fn train(&self, runs: &[(u128, usize)]) -> IntervalCompressor {
+        let mut run_ip_addrs_sorted: Vec<u128> = runs.iter().map(|&(ip_addr, _)| ip_addr).collect();
+        run_ip_addrs_sorted.sort();
+        train(&run_ip_addrs_sorted, self.0)
+    }
+}
+
+impl IpRepr for RunLengthEncoding {
+    fn codec_id(&self) -> CodecId {
+        CodecId::RunLength
+    }
+
+    fn params(&self) -> Vec<u64> {
+        vec![self.0 as u64]
+    }
+
+    fn encode_with_stats(
+        &self,
+        ip_addrs: &[u128],
+    ) -> Result<(Vec<u8>, CompressionStats), IpReprError> {
+        let mut stats = CompressionStats::default();
+        if ip_addrs.is_empty() {
+            return Ok((Vec::new(), stats));
+        }
+        let start = Instant::now();
+        let runs = runs(ip_addrs);
+        let compressor = self.train(&runs);
+        stats.train_time = start.elapsed();
+        let start = Instant::now();
+        let compressed = compress(&compressor, ip_addrs.len(), &runs, &mut stats);
+        stats.compress_time = start.elapsed();
+        Ok((compressed, stats))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u128>, IpReprError> {
+        let reader = RunLengthReader::open(data)?;
+        Ok(reader.iter().collect())
+    }
+
+    fn matches_cidr(&self, data: &[u8], cidrs: &[Cidr]) -> Result<BitSet, IpReprError> {
+        let reader = RunLengthReader::open(data)?;
+        Ok(reader.matches_cidr(cidrs))
+    }
+}
+
+/// Random access reader over a column encoded with `RunLengthEncoding`.
+///
+/// `open` checks the value and the start of each run once. A value is then fetched
+/// in O(log num_runs), by binary search over the bitpacked run starts.
+pub struct RunLengthReader<'a> {
+    decompressor: IntervallDecompressor,
+    run_start_unpacker: BitUnpacker,
+    /// Bitpacked row at which each run starts.
+    run_starts_data: &'a [u8],
+    num_runs: u32,
+    num_vals: u32,
+    data: &'a [u8],
+}
+
+impl<'a> RunLengthReader<'a> {
+    pub fn open(data: &'a [u8]) -> Result<RunLengthReader<'a>, IpReprError> {
+        if data.is_empty() {
+            return Ok(RunLengthReader {
+                decompressor: IntervallDecompressor::default(),
+                run_start_unpacker: BitUnpacker::new(0),
+                run_starts_data: data,
+                num_runs: 0,
+                num_vals: 0,
+                data,
+            });
+        }
+        let (decompressor, data) = IntervallDecompressor::open(data)?;
+        let (num_vals, data) = deserialize_num_vals(data)?;
+        let (num_runs, data) = deserialize_num_vals(data)?;
+        // Consecutive runs have different values, so a compact space of a single
+        // value holds a single run.
+        if num_runs == 0 || num_runs > num_vals || (decompressor.num_bits == 0 && num_runs > 1) {
+            return Err(IpReprError::CorruptedData);
+        }
+        let (&num_bits_run_start, data) = data.split_first().ok_or(IpReprError::TruncatedHeader)?;
+        if num_bits_run_start != compute_num_bits(num_vals as u64 - 1) {
+            return Err(IpReprError::InvalidNumBits(num_bits_run_start));
+        }
+        check_bitpacked_len(num_runs, decompressor.num_bits, data)?;
+        let values_num_bytes = (num_runs * decompressor.num_bits as usize).div_ceil(8);
+        let run_starts_data = &data[values_num_bytes..];
+        check_bitpacked_len(num_runs, num_bits_run_start, run_starts_data)?;
+        let reader = RunLengthReader {
+            decompressor,
+            run_start_unpacker: BitUnpacker::new(num_bits_run_start),
+            run_starts_data,
+            num_runs: num_runs as u32,
+            num_vals: num_vals as u32,
+            data,
+        };
+        if reader.run_start(0) != 0 {
+            return Err(IpReprError::CorruptedData);
+        }
+        for run in 0..reader.num_runs() {
+            reader.decompressor.checked_get(run, reader.data)?;
+            if reader.run_start(run + 1) <= reader.run_start(run) {
+                return Err(IpReprError::CorruptedData);
+            }
+        }
+        Ok(reader)
+    }
+
+    fn run_value(&self, run: usize) -> u128 {
+        self.decompressor.get(run, self.data)
+    }
+
+    /// Returns the row at which `run` starts, `self.len()` past the last run.
+    fn run_start(&self, run: usize) -> u32 {
+        if run == self.num_runs() {
+            return self.num_vals;
+        }
+        let run_start = self
+            .run_start_unpacker
+            .get(run as u64, self.run_starts_data);
+        (run_start as u32).min(self.num_vals)
+    }
+
+    /// Returns the range of rows of `run`.
+    fn run_rows(&self, run: usize) -> std::ops::Range<u32> {
+        self.run_start(run)..self.run_start(run + 1)
+    }
+
+    /// Returns the value at `row`.
+    ///
+    /// Panics if `row >= self.len()`.
+    pub fn get(&self, row: u32) -> u128 {
+        assert!(row < self.num_vals, "row {row} out of bounds");
+        // Last run starting at or before `row`, the first one starting at 0.
+        let (mut lo, mut hi) = (1, self.num_runs());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.run_start(mid) <= row {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        self.run_value(lo - 1)
+    }
+
+    /// Returns the ip address at `row`, IPv4-mapped addresses being returned as IPv4.
+    ///
+    /// Panics if `row` is out of bounds.
+    pub fn get_ip(&self, row: u32) -> IpAddr {
+        u128_to_ip_addr(self.get(row))
+    }
+
+    /// Returns the number of runs of repeated values.
+    pub fn num_runs(&self) -> usize {
+        self.num_runs as usize
+    }
+
+    /// Returns the rows whose value is in any of `cidrs`, checking each run once.
+    pub fn matches_cidr(&self, cidrs: &[Cidr]) -> BitSet {
+        let mut bitset = BitSet::with_max_value(self.num_vals);
+        for run in 0..self.num_runs() {
+            let ip_addr = self.run_value(run);
+            if cidrs.iter().any(|cidr| cidr.contains(ip_addr)) {
+                for row in self.run_rows(run) {
+                    bitset.insert(row);
+                }
+            }
+        }
+        bitset
+    }
+
+    pub fn len(&self) -> u32 {
+        self.num_vals
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_vals == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u128> + '_ {
+        (0..self.num_runs()).flat_map(move |run| {
+            std::iter::repeat_n(self.run_value(run), self.run_rows(run).len())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::{
+        tests::{check_random_access, check_truncated, edge_case_ip_addrs, random_vals},
+        IntervalEncoding,
+    };
+
+    /// Sessions of random lengths, each from a client among 500.
+    fn bursty_ip_addrs(num_vals: usize) -> Vec<u128> {
+        let mut ip_addrs = Vec::with_capacity(num_vals);
+        let mut randoms = random_vals(num_vals, 3);
+        while ip_addrs.len() < num_vals {
+            let random = randoms.next().unwrap();
+            let ip_addr = 0xffff_0a00_0000 + (random % 500) as u128;
+            let session_len = 1 + (random >> 32) as usize % 50;
+            let session_len = session_len.min(num_vals - ip_addrs.len());
+            ip_addrs.extend(std::iter::repeat_n(ip_addr, session_len));
+        }
+        ip_addrs
+    }
+
+    fn test_aux_vals(ip_addrs: &[u128]) -> Vec<u8> {
+        check_random_access(&RunLengthEncoding::default(), ip_addrs, |data| {
+            let reader = RunLengthReader::open(data).unwrap();
+            (0..reader.len()).map(|row| reader.get(row)).collect()
+        })
+    }
+
+    #[test]
+    fn test_run_length() {
+        let ip_addrs = bursty_ip_addrs(20_000);
+        let data = test_aux_vals(&ip_addrs);
+        let data_interval = IntervalEncoding::default().encode(&ip_addrs).unwrap();
+        assert!(data.len() * 4 < data_interval.len());
+        for ip_addrs in edge_case_ip_addrs() {
+            test_aux_vals(&ip_addrs);
+        }
+    }
+
+    #[test]
+    fn test_run_length_reader() {
+        let ip_addrs = [3, 3, 3, 0xffff_0a00_0001, 5, 5];
+        let data = RunLengthEncoding::default().encode(&ip_addrs).unwrap();
+        let reader = RunLengthReader::open(&data).unwrap();
+        assert_eq!(reader.num_runs(), 3);
+        assert!(!reader.is_empty());
+        assert_eq!(reader.get_ip(3), IpAddr::from_str("10.0.0.1").unwrap());
+        let cidrs = [
+            Cidr::from_str("10.0.0.0/8").unwrap(),
+            Cidr::from_str("::5/128").unwrap(),
+        ];
+        assert_eq!(
+            reader.matches_cidr(&cidrs).iter().collect::<Vec<_>>(),
+            vec![3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn test_run_length_stats() {
+        let ip_addrs = bursty_ip_addrs(5000);
+        let (data, stats) = RunLengthEncoding::default()
+            .encode_with_stats(&ip_addrs)
+            .unwrap();
+        assert_eq!(stats.num_bytes(), data.len());
+        assert_eq!(stats.num_runs, Some(runs(&ip_addrs).len()));
+    }
+
+    #[test]
+    fn test_run_length_truncated() {
+        let data = RunLengthEncoding::default()
+            .encode(&bursty_ip_addrs(1000))
+            .unwrap();
+        check_truncated(&data, |data| RunLengthReader::open(data).is_err());
+    }
+
+    #[test]
+    fn test_run_length_corrupted_run_starts() {
+        let ip_addrs = bursty_ip_addrs(300);
+        let data = RunLengthEncoding::default().encode(&ip_addrs).unwrap();
+        let num_runs = runs(&ip_addrs).len();
+        // The run starts are the last bytes before the padding.
+        let run_starts_num_bytes = (num_runs * 9).div_ceil(8);
+        for pos in data.len() - 7 - run_starts_num_bytes..data.len() - 7 {
+            let mut corrupted = data.clone();
+            corrupted[pos] ^= 0xa5;
+            if let Ok(decoded) = RunLengthEncoding::default().decode(&corrupted) {
+                assert_eq!(decoded.len(), ip_addrs.len());
+            }
+            let Ok(reader) = RunLengthReader::open(&corrupted) else {
+                continue;
+            };
+            for row in 0..reader.len() {
+                reader.get(row);
+            }
+            assert_eq!(reader.iter().count(), reader.len() as usize);
+            reader.matches_cidr(&[Cidr::from_str("10.0.0.0/8").unwrap()]);
+        }
+    }
+    #[test]
+    fn test_run_length_value_outside_of_domain() {
+        // The compact space is 0..8, of which 5 and above map past u128::MAX.
+        let ip_addrs = &[0, 1, 2, u128::MAX - 1, u128::MAX];
+        let mut data = test_aux_vals(ip_addrs);
+        let (decompressor, rest) = IntervallDecompressor::open(&data).unwrap();
+        assert_eq!(decompressor.num_bits, 3);
+        let (_, rest) = deserialize_num_vals(rest).unwrap();
+        let (_, rest) = deserialize_num_vals(rest).unwrap();
+        // Skips the number of bits per run start.
+        let values_start = data.len() - rest.len() + 1;
+        data[values_start] |= 0b111;
+        assert!(matches!(
+            RunLengthReader::open(&data),
+            Err(IpReprError::CorruptedData)
+        ));
+        assert_eq!(
+            RunLengthEncoding::default().decode(&data),
+            Err(IpReprError::CorruptedData)
+        );
+    }
+}
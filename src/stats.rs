@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+/// Statistics collected while encoding a column, see `IpRepr::encode_with_stats`.
+///
+/// Fields that do not apply to a codec are left to `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompressionStats {
+    pub num_vals: usize,
+    /// Size of the metadata preceding the values: interval table, dictionary...
+    pub header_num_bytes: usize,
+    /// Size of the encoded values.
+    pub payload_num_bytes: usize,
+    pub num_intervals: Option<usize>,
+    /// Number of bits per bitpacked value in compact space.
+    pub num_bits: Option<u8>,
+    /// Number of dictionary entries.
+    pub dict_size: Option<usize>,
+    /// Number of bits per bitpacked dictionary ordinal.
+    pub dict_num_bits: Option<u8>,
+    /// Number of runs of repeated values.
+    pub num_runs: Option<usize>,
+    pub train_time: Duration,
+    pub compress_time: Duration,
+}
+
+impl CompressionStats {
+    pub fn num_bytes(&self) -> usize {
+        self.header_num_bytes + self.payload_num_bytes
+    }
+
+    pub fn bits_per_element(&self) -> f64 {
+        if self.num_vals == 0 {
+            return 0.0;
+        }
+        (8 * self.num_bytes()) as f64 / self.num_vals as f64
+    }
+
+    /// Adds the stats of another chunk of the same column. Counts and sizes are
+    /// summed, bit widths are the largest of both.
+    pub(crate) fn merge(&mut self, other: &CompressionStats) {
+        fn merge_opt<T>(left: Option<T>, right: Option<T>, f: impl Fn(T, T) -> T) -> Option<T> {
+            match (left, right) {
+                (Some(left), Some(right)) => Some(f(left, right)),
+                (left, right) => left.or(right),
+            }
+        }
+        self.num_vals += other.num_vals;
+        self.header_num_bytes += other.header_num_bytes;
+        self.payload_num_bytes += other.payload_num_bytes;
+        self.num_intervals = merge_opt(self.num_intervals, other.num_intervals, |l, r| l + r);
+        self.num_bits = merge_opt(self.num_bits, other.num_bits, u8::max);
+        self.dict_size = merge_opt(self.dict_size, other.dict_size, |l, r| l + r);
+        self.dict_num_bits = merge_opt(self.dict_num_bits, other.dict_num_bits, u8::max);
+        self.num_runs = merge_opt(self.num_runs, other.num_runs, |l, r| l + r);
+        self.train_time += other.train_time;
+        self.compress_time += other.compress_time;
+    }
+
+    /// Size of the encoded column relative to 16 bytes per ip address.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.num_vals == 0 {
+            return 0.0;
+        }
+        self.num_bytes() as f64 / (16 * self.num_vals) as f64
+    }
+}
+
+/// Encoded size of a column, as estimated by `IpRepr::estimate`.
+///
+/// When the column has no more values than the sample, the sample is the column
+/// itself and the estimate is exact.
+///
+/// Otherwise the payload of the sample is extrapolated linearly to the whole
+/// column while its header is kept as is. For a column whose distribution does
+/// not drift along the rows, the estimate is expected within 25% of the actual
+/// size. The header of the column (interval table, dictionary) is typically
+/// larger than the one of the sample, so the error is mostly an underestimation,
+/// bounded by the growth of the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EstimatedSize {
+    pub num_vals: usize,
+    /// Number of values actually encoded to compute the estimate.
+    pub sample_len: usize,
+    pub num_bytes: usize,
+}
+
+impl EstimatedSize {
+    /// Extrapolates the stats of a sample to a column of `num_vals` values.
+    pub(crate) fn extrapolate(sample_stats: &CompressionStats, num_vals: usize) -> EstimatedSize {
+        let sample_len = sample_stats.num_vals;
+        let num_bytes = if sample_len == num_vals {
+            sample_stats.num_bytes()
+        } else {
+            let payload_num_bytes =
+                sample_stats.payload_num_bytes as u128 * num_vals as u128 / sample_len as u128;
+            sample_stats.header_num_bytes + payload_num_bytes as usize
+        };
+        EstimatedSize {
+            num_vals,
+            sample_len,
+            num_bytes,
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        self.sample_len == self.num_vals
+    }
+
+    pub fn bits_per_element(&self) -> f64 {
+        if self.num_vals == 0 {
+            return 0.0;
+        }
+        (8 * self.num_bytes) as f64 / self.num_vals as f64
+    }
+}
@@ -0,0 +1,137 @@
+use std::{io::Read, time::Instant};
+
+use crate::{
+    interval::{deserialize_num_vals, serialize_vint},
+    CodecId, CompressionStats, IpRepr, IpReprError,
+};
+
+/// Compresses the little endian bytes of the ip addresses with zstd, at the
+/// given compression level.
+///
+/// It serves as a generic baseline for the other codecs.
+///
+/// Layout:
+/// - number of values
+/// - the zstd frame of the values, 16 bytes each
+#[derive(Debug)]
+pub struct ZstdEncoding(pub i32);
+
+impl Default for ZstdEncoding {
+    fn default() -> Self {
+        ZstdEncoding(3)
+    }
+}
+
+impl IpRepr for ZstdEncoding {
+    fn codec_id(&self) -> CodecId {
+        CodecId::Zstd
+    }
+
+    /// The level is stored as the bits of the `i32`, so negative levels round trip.
+    fn params(&self) -> Vec<u64> {
+        vec![self.0.cast_unsigned() as u64]
+    }
+
+    fn encode_with_stats(
+        &self,
+        ip_addrs: &[u128],
+    ) -> Result<(Vec<u8>, CompressionStats), IpReprError> {
+        let mut stats = CompressionStats {
+            num_vals: ip_addrs.len(),
+            ..Default::default()
+        };
+        if ip_addrs.is_empty() {
+            return Ok((Vec::new(), stats));
+        }
+        let start = Instant::now();
+        let bytes: Vec<u8> = ip_addrs
+            .iter()
+            .flat_map(|ip_addr| ip_addr.to_le_bytes())
+            .collect();
+        let mut output = vec![];
+        serialize_vint(ip_addrs.len() as u128, &mut output);
+        stats.header_num_bytes = output.len();
+        zstd::stream::copy_encode(&*bytes, &mut output, self.0).unwrap();
+        stats.compress_time = start.elapsed();
+        stats.payload_num_bytes = output.len() - stats.header_num_bytes;
+        Ok((output, stats))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u128>, IpReprError> {
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (num_vals, data) = deserialize_num_vals(data)?;
+        let num_bytes = num_vals as u64 * 16;
+        // Reads one byte past the expected length, to detect frames that are too long
+        // without decompressing them entirely.
+        let mut bytes = vec![];
+        zstd::stream::read::Decoder::new(data)
+            .and_then(|decoder| decoder.take(num_bytes + 1).read_to_end(&mut bytes))
+            .map_err(|_| IpReprError::CorruptedData)?;
+        match (bytes.len() as u64).cmp(&num_bytes) {
+            std::cmp::Ordering::Less => return Err(IpReprError::TruncatedData),
+            std::cmp::Ordering::Greater => return Err(IpReprError::CorruptedData),
+            std::cmp::Ordering::Equal => {}
+        }
+        let ip_addrs = bytes
+            .chunks_exact(16)
+            .map(|chunk| u128::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        Ok(ip_addrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zstd_round_trip() {
+        let ip_addrs: Vec<u128> = (0..1000u128).map(|i| 0xffff_0a00_0000 + i % 17).collect();
+        let zstd_encoding = ZstdEncoding::default();
+        let data = zstd_encoding.encode(&ip_addrs).unwrap();
+        assert!(data.len() < ip_addrs.len());
+        assert_eq!(zstd_encoding.decode(&data).unwrap(), ip_addrs);
+        assert!(zstd_encoding.decode(&data[..data.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn test_zstd_format_is_independent_of_the_domain() {
+        let data = ZstdEncoding::default().encode(&[1, 2, 3]).unwrap();
+        let (num_vals, data) = deserialize_num_vals(&data).unwrap();
+        assert_eq!(num_vals, 3);
+        let mut bytes = vec![];
+        zstd::stream::copy_decode(data, &mut bytes).unwrap();
+        assert_eq!(bytes.len(), 3 * 16);
+    }
+    #[test]
+    fn test_zstd_negative_level() {
+        let zstd_encoding = ZstdEncoding(-5);
+        assert_eq!(zstd_encoding.params(), vec![(-5i32).cast_unsigned() as u64]);
+        let ip_addrs: Vec<u128> = (0..1000u128).map(|i| 0xffff_0a00_0000 + i % 17).collect();
+        let data = zstd_encoding.encode(&ip_addrs).unwrap();
+        assert_eq!(zstd_encoding.decode(&data).unwrap(), ip_addrs);
+    }
+
+    #[test]
+    fn test_zstd_num_vals_mismatch() {
+        let zstd_encoding = ZstdEncoding::default();
+        let data = zstd_encoding.encode(&[1, 2, 3]).unwrap();
+        // The number of values fits on the first byte.
+        let mut more_vals = vec![];
+        serialize_vint(4, &mut more_vals);
+        more_vals.extend_from_slice(&data[1..]);
+        assert_eq!(
+            zstd_encoding.decode(&more_vals),
+            Err(IpReprError::TruncatedData)
+        );
+        let mut fewer_vals = vec![];
+        serialize_vint(2, &mut fewer_vals);
+        fewer_vals.extend_from_slice(&data[1..]);
+        assert_eq!(
+            zstd_encoding.decode(&fewer_vals),
+            Err(IpReprError::CorruptedData)
+        );
+    }
+}